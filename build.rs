@@ -3,5 +3,8 @@ fn main() {
     {
         pkg_config::Config::new().atleast_version("1").probe("x11").unwrap();
         pkg_config::Config::new().atleast_version("1").probe("xtst").unwrap();
+        pkg_config::Config::new().atleast_version("1").probe("xext").unwrap();
+        pkg_config::Config::new().atleast_version("1").probe("xrandr").unwrap();
+        pkg_config::Config::new().atleast_version("0.2").probe("xcomposite").unwrap();
     }
 }
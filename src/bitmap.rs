@@ -11,10 +11,26 @@
 //! It also defines functions for taking screenshots of the screen.
 extern crate image;
 
+use crate::color::{self, Hsv, Lab};
+use crate::correlation::{ncc_scores, Planes};
+use crate::encoding;
+use crate::features::{self, FeatureMatch};
+use crate::font;
 use crate::geometry::{Point, Rect, Size};
-use image::{DynamicImage, GenericImage, GenericImageView, ImageError, ImageResult, Pixel, Rgba};
-use image::error::{LimitError, LimitErrorKind};
+use crate::pyramid::{Grid, TemplateSearch};
+use image::imageops::FilterType;
+use image::{
+    DynamicImage, GenericImageView, ImageError, ImageFormat, ImageResult, Pixel, Rgb,
+    Rgba, RgbaImage,
+};
+use image::error::{DecodingError, EncodingError, ImageFormatHint, LimitError, LimitErrorKind};
+use crate::mouse::{self, MouseError};
 use crate::screen;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::io::BufWriter;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
 
 #[cfg(target_os = "macos")]
 use core_graphics::geometry::CGRect;
@@ -25,11 +41,15 @@ use libc;
 
 #[cfg(target_os = "linux")]
 use crate::internal;
+#[cfg(target_os = "linux")]
+use crate::shm;
 #[cfg(not(target_os = "macos"))]
 use scopeguard::guard;
 
 #[derive(Clone)]
 pub struct Bitmap {
+    /// Pixels of the bitmap, stored as RGBA by `Bitmap::new` so that they
+    /// can be accessed directly using `pixels`.
     pub image: DynamicImage,
 
     /// Size of the bitmap in points.
@@ -38,6 +58,571 @@ pub struct Bitmap {
     pub scale: f64,
 }
 
+/// A read-only view of RGBA pixels stored contiguously in rows, as returned
+/// by `Bitmap::pixels`.
+///
+/// Rows are `stride` bytes apart, which may be more than the width of the
+/// view when it covers only part of a bitmap.
+#[derive(Clone, Debug)]
+pub struct PixelView<'a> {
+    data: Cow<'a, [u8]>,
+    width: u32,
+    height: u32,
+    stride: usize,
+}
+
+impl<'a> PixelView<'a> {
+    /// Returns a view of `data`, which holds `height` rows of `width` RGBA
+    /// pixels spaced `stride` bytes apart.
+    pub fn new(data: &'a [u8], width: u32, height: u32, stride: usize) -> PixelView<'a> {
+        assert!(
+            stride >= width as usize * 4
+                && (height == 0 || data.len() >= stride * (height as usize - 1) + width as usize * 4),
+            "Pixel data is too short for the given size."
+        );
+        PixelView {
+            data: Cow::Borrowed(data),
+            width,
+            height,
+            stride,
+        }
+    }
+
+    #[inline]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the number of bytes from the start of one row to the next.
+    #[inline]
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Returns the RGBA bytes of the row at `y`.
+    #[inline]
+    pub fn row(&self, y: u32) -> &[u8] {
+        assert!(y < self.height, "Row {} out of bounds.", y);
+        let start = y as usize * self.stride;
+        &self.data[start..start + self.width as usize * 4]
+    }
+
+    /// Returns an iterator over the RGBA bytes of each row, from the top.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    /// Returns the color of the pixel at `(x, y)`.
+    #[inline]
+    pub fn get(&self, x: u32, y: u32) -> Rgba<u8> {
+        assert!(
+            x < self.width && y < self.height,
+            "Pixel ({}, {}) out of bounds.",
+            x,
+            y
+        );
+        let offset = y as usize * self.stride + x as usize * 4;
+        let pixel = &self.data[offset..offset + 4];
+        Rgba([pixel[0], pixel[1], pixel[2], pixel[3]])
+    }
+
+    /// Returns a view of the given portion of this view, sharing its data.
+    pub fn view(&self, x: u32, y: u32, width: u32, height: u32) -> PixelView<'_> {
+        assert!(
+            x.checked_add(width).is_some_and(|max_x| max_x <= self.width)
+                && y.checked_add(height).is_some_and(|max_y| max_y <= self.height),
+            "View out of bounds."
+        );
+        let start = (y as usize * self.stride + x as usize * 4).min(self.data.len());
+        PixelView {
+            data: Cow::Borrowed(&self.data[start..]),
+            width,
+            height,
+            stride: self.stride,
+        }
+    }
+}
+
+/// How pixels are compared when searching for a bitmap.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Compares colors exactly as they are.
+    #[default]
+    Rgb,
+
+    /// Compares brightness only, ignoring hue and saturation.
+    Grayscale,
+
+    /// Compares the strength of edges found by a Sobel filter, ignoring flat
+    /// areas of color. This allows a needle to match across light and dark
+    /// themes or hover states, as long as its outline stays the same. The
+    /// outermost pixels of the needle are ignored, since their edges depend
+    /// on whatever surrounds them.
+    Edges,
+}
+
+/// Filter used to resample a bitmap, as accepted by `Bitmap::rescaled`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ResampleFilter {
+    /// Takes the nearest pixel, keeping edges sharp. Best for pixel art and
+    /// for scaling by whole factors.
+    Nearest,
+
+    /// Interpolates linearly between the nearest pixels.
+    #[default]
+    Bilinear,
+
+    /// Uses a Lanczos window with a radius of 3. Slowest, but keeps the most
+    /// detail, especially when scaling down.
+    Lanczos,
+}
+
+impl ResampleFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Bilinear => FilterType::Triangle,
+            ResampleFilter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How the difference between two colors is measured when comparing them
+/// within a tolerance.
+///
+/// Each metric is normalized so that a tolerance of 0 is an exact match and
+/// 1 matches anything.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ColorMetric {
+    /// Euclidean distance between RGB values, as used by `find_color`.
+    #[default]
+    Rgb,
+
+    /// Euclidean distance between colors in CIELAB, divided by 100. A
+    /// tolerance of 0.01 is roughly the smallest difference noticeable by
+    /// eye, for dark and light colors alike.
+    Cie76,
+
+    /// CIEDE2000 color difference, divided by 100. This corrects CIE76 for
+    /// saturated colors and blues, at the cost of being slower to compute.
+    Ciede2000,
+
+    /// Largest difference of any single RGB channel, divided by 255.
+    ChannelMax,
+}
+
+impl ColorMetric {
+    /// Returns the difference between the two colors, from 0 to 1. Alpha is
+    /// ignored.
+    pub fn distance(&self, c1: Rgba<u8>, c2: Rgba<u8>) -> f64 {
+        match self {
+            ColorMetric::Rgb => {
+                let distance = (0..3)
+                    .map(|i| (f64::from(c1[i]) - f64::from(c2[i])).powi(2))
+                    .sum::<f64>()
+                    .sqrt();
+                distance / MAX_TOLERANCE_DELTA
+            }
+            ColorMetric::Cie76 => {
+                (color::cie76(Lab::from_rgba(c1), Lab::from_rgba(c2)) / 100.0).min(1.0)
+            }
+            ColorMetric::Ciede2000 => {
+                (color::ciede2000(Lab::from_rgba(c1), Lab::from_rgba(c2)) / 100.0).min(1.0)
+            }
+            ColorMetric::ChannelMax => {
+                (0..3)
+                    .map(|i| c1[i].abs_diff(c2[i]))
+                    .max()
+                    .map_or(0.0, f64::from)
+                    / 255.0
+            }
+        }
+    }
+
+    /// Returns true if the given two colors are within `tolerance` of each
+    /// other. As with `find_color`, a tolerance of 0 requires the colors to
+    /// be identical, including alpha.
+    pub fn matches(&self, c1: Rgba<u8>, c2: Rgba<u8>, tolerance: f64) -> bool {
+        match self {
+            ColorMetric::Rgb => colors_match(c1, c2, tolerance),
+            _ => {
+                assert!(
+                    (0.0..=1.0).contains(&tolerance),
+                    "Tolerance must be between 0 and 1."
+                );
+                if tolerance == 0.0 {
+                    c1 == c2
+                } else {
+                    self.distance(c1, c2) <= tolerance
+                }
+            }
+        }
+    }
+
+    /// Returns true if the given two colors are within `tolerance` of each
+    /// other, with the tolerance loosened by `weight` as in
+    /// `weighted_colors_match`.
+    pub(crate) fn weighted_matches(
+        &self,
+        c1: Rgba<u8>,
+        c2: Rgba<u8>,
+        weight: f64,
+        tolerance: f64,
+    ) -> bool {
+        match self {
+            ColorMetric::Rgb => weighted_colors_match(c1, c2, weight, tolerance),
+            _ => {
+                assert!(
+                    (0.0..=1.0).contains(&tolerance),
+                    "Tolerance must be between 0 and 1."
+                );
+                let tolerance = tolerance + (1.0 - weight.clamp(0.0, 1.0));
+                if tolerance >= 1.0 {
+                    true
+                } else if tolerance == 0.0 {
+                    c1.to_rgb() == c2.to_rgb()
+                } else {
+                    self.distance(c1, c2) <= tolerance
+                }
+            }
+        }
+    }
+
+    /// Returns the largest difference between the sums of the RGB channels
+    /// of two colors that match within `tolerance`, or `None` if the metric
+    /// does not bound it.
+    pub(crate) fn channel_sum_bound(&self, tolerance: f64) -> Option<f64> {
+        match self {
+            // A Euclidean distance of `d` bounds the difference of the
+            // channel sums by `d * sqrt(3)`.
+            ColorMetric::Rgb => Some(3f64.sqrt() * tolerance * MAX_TOLERANCE_DELTA),
+            ColorMetric::ChannelMax => Some(3.0 * tolerance * 255.0),
+            ColorMetric::Cie76 | ColorMetric::Ciede2000 => None,
+        }
+    }
+}
+
+/// A range of colors to search for, as accepted by
+/// `Bitmap::find_color_in_range`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorRange(ColorBounds);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ColorBounds {
+    Rgb {
+        min: Rgb<u8>,
+        max: Rgb<u8>,
+    },
+    Hsv {
+        hue: (f64, f64),
+        saturation: (f64, f64),
+        value: (f64, f64),
+    },
+}
+
+impl ColorRange {
+    /// Returns a range of colors between `min` and `max` in each RGB
+    /// channel, inclusive.
+    #[inline]
+    pub fn rgb(min: Rgb<u8>, max: Rgb<u8>) -> ColorRange {
+        ColorRange(ColorBounds::Rgb { min, max })
+    }
+
+    /// Returns a range of colors whose hue, in degrees, saturation and
+    /// value, from 0 to 1, lie within the given bounds, inclusive, or `None`
+    /// if a bound is out of range or a saturation or value range is
+    /// decreasing.
+    ///
+    /// If the start of `hue` is greater than its end, the range wraps around
+    /// 360 degrees, e.g. from 340 to 20 for reds. Grays have a hue of 0, so
+    /// it is usually worth requiring a minimum saturation.
+    pub fn hsv(
+        hue: RangeInclusive<f64>,
+        saturation: RangeInclusive<f64>,
+        value: RangeInclusive<f64>,
+    ) -> Option<ColorRange> {
+        let hue_valid = [hue.start(), hue.end()]
+            .iter()
+            .all(|bound| (0.0..=360.0).contains(*bound));
+        let unit_valid = |range: &RangeInclusive<f64>| {
+            range.start() <= range.end()
+                && (0.0..=1.0).contains(range.start())
+                && (0.0..=1.0).contains(range.end())
+        };
+        (hue_valid && unit_valid(&saturation) && unit_valid(&value)).then(|| {
+            ColorRange(ColorBounds::Hsv {
+                hue: hue.into_inner(),
+                saturation: saturation.into_inner(),
+                value: value.into_inner(),
+            })
+        })
+    }
+
+    /// Returns true if `color` lies within the range. Alpha is ignored.
+    pub fn contains(&self, color: Rgba<u8>) -> bool {
+        match self.0 {
+            ColorBounds::Rgb { min, max } => {
+                (0..3).all(|i| (min[i]..=max[i]).contains(&color[i]))
+            }
+            ColorBounds::Hsv {
+                hue: (min_hue, max_hue),
+                saturation: (min_saturation, max_saturation),
+                value: (min_value, max_value),
+            } => {
+                let hsv = Hsv::from_rgba(color);
+                let hue_matches = if min_hue <= max_hue {
+                    (min_hue..=max_hue).contains(&hsv.hue)
+                } else {
+                    hsv.hue >= min_hue || hsv.hue <= max_hue
+                };
+                hue_matches
+                    && (min_saturation..=max_saturation).contains(&hsv.saturation)
+                    && (min_value..=max_value).contains(&hsv.value)
+            }
+        }
+    }
+}
+
+/// A needle found inside a bitmap.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Match {
+    /// Bounds of the match inside the searched bitmap, in points.
+    pub rect: Rect,
+
+    /// Similarity of the match, defined as a float in the range from 0 to 1,
+    /// where 1 is a perfect match.
+    pub score: f64,
+
+    /// Scale of the needle that matched, after any resampling.
+    pub scale: f64,
+}
+
+impl Match {
+    #[inline]
+    /// Returns the center of the match.
+    pub fn center(&self) -> Point {
+        self.rect.center()
+    }
+
+    /// Returns the match moved by the given offset, e.g. the origin of the
+    /// screen portion that was searched.
+    pub fn offset(&self, offset: Point) -> Match {
+        let origin = Point::new(self.rect.origin.x + offset.x, self.rect.origin.y + offset.y);
+        Match {
+            rect: Rect::new(origin, self.rect.size),
+            ..*self
+        }
+    }
+
+    /// Moves the mouse to the center of the match and clicks the given
+    /// button. See `mouse::click` for how `delay_ms` is used.
+    ///
+    /// The match is assumed to be in screen coordinates, e.g. found in
+    /// `capture_screen()`; use `offset` to convert matches found in a portion
+    /// of the screen.
+    ///
+    /// Returns `MouseError` if the center is outside the screen boundaries.
+    pub fn click(&self, button: mouse::Button, delay_ms: Option<u64>) -> Result<(), MouseError> {
+        mouse::move_to(self.center())?;
+        mouse::click(button, delay_ms);
+        Ok(())
+    }
+}
+
+/// Criteria for two matches to be considered the same occurrence of a
+/// needle.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overlap {
+    /// Matches whose origins are less than the given distance apart, in
+    /// points.
+    MinDistance(f64),
+
+    /// Matches whose intersection over union is greater than the given
+    /// ratio, defined as a float in the range from 0 to 1. A ratio of 0
+    /// treats any overlapping matches as the same.
+    IntersectionOverUnion(f64),
+}
+
+impl Overlap {
+    /// Returns true if `a` and `b` are the same occurrence.
+    pub fn is_overlapping(&self, a: &Match, b: &Match) -> bool {
+        match *self {
+            Overlap::MinDistance(distance) => {
+                (a.rect.origin.x - b.rect.origin.x).hypot(a.rect.origin.y - b.rect.origin.y)
+                    < distance
+            }
+            Overlap::IntersectionOverUnion(ratio) => {
+                a.rect.intersection(b.rect).is_some_and(|intersection| {
+                    let area = |rect: Rect| rect.size.width * rect.size.height;
+                    let overlap = area(intersection);
+                    overlap / (area(a.rect) + area(b.rect) - overlap) > ratio
+                })
+            }
+        }
+    }
+}
+
+/// Returns one match per occurrence, keeping the highest scoring of each
+/// group of overlapping matches. Ties keep the earlier match, and the
+/// matches kept are returned in their original order.
+pub fn suppress_overlapping_matches(matches: &[Match], overlap: Overlap) -> Vec<Match> {
+    let mut by_score: Vec<usize> = (0..matches.len()).collect();
+    by_score.sort_by(|&a, &b| matches[b].score.total_cmp(&matches[a].score));
+
+    let mut kept: Vec<usize> = Vec::new();
+    for idx in by_score {
+        if !kept
+            .iter()
+            .any(|&other| overlap.is_overlapping(&matches[idx], &matches[other]))
+        {
+            kept.push(idx);
+        }
+    }
+    kept.sort_unstable();
+    kept.into_iter().map(|idx| matches[idx]).collect()
+}
+
+/// An inclusive range of factors to resample a needle by when searching
+/// across display scales.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScaleRange {
+    min: f64,
+    max: f64,
+    step: f64,
+}
+
+impl ScaleRange {
+    /// Returns the factors from `min` to `max` in increments of `step`, or
+    /// `None` if they are not finite, positive and increasing.
+    pub fn new(min: f64, max: f64, step: f64) -> Option<ScaleRange> {
+        let valid = [min, max, step].iter().all(|value| value.is_finite())
+            && min > 0.0
+            && step > 0.0
+            && min <= max;
+        valid.then_some(ScaleRange { min, max, step })
+    }
+
+    /// Returns a range containing only the given factor, or `None` if it is
+    /// not finite and positive.
+    #[inline]
+    pub fn exact(factor: f64) -> Option<ScaleRange> {
+        ScaleRange::new(factor, factor, 1.0)
+    }
+
+    #[inline]
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    #[inline]
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    #[inline]
+    pub fn step(&self) -> f64 {
+        self.step
+    }
+
+    /// Returns each factor in the range, from `min` to `max`.
+    pub fn factors(&self) -> Vec<f64> {
+        let count = ((self.max - self.min) / self.step + 1e-9).floor() as u64;
+        (0..=count)
+            .map(|i| ((self.min + i as f64 * self.step) * 1e6).round() / 1e6)
+            .collect()
+    }
+}
+
+/// Covers factors from 0.25 to 3 in steps of 0.25, which includes common
+/// display scales such as 1.25, 1.5 and 2.
+impl Default for ScaleRange {
+    fn default() -> ScaleRange {
+        ScaleRange {
+            min: 0.25,
+            max: 3.0,
+            step: 0.25,
+        }
+    }
+}
+
+/// Algorithm used to compute the perceptual hash of a bitmap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    /// Compares each pixel of an 8x8 grayscale thumbnail to its mean. Fast,
+    /// but sensitive to changes in brightness.
+    Average,
+
+    /// Compares each pixel of a 9x8 grayscale thumbnail to its neighbor on
+    /// the right, recording the direction of gradients.
+    Difference,
+
+    /// Compares the lowest frequencies of the discrete cosine transform of a
+    /// 32x32 grayscale thumbnail to their median. Slowest, but the most
+    /// robust to scaling, compression and small edits.
+    Perceptual,
+}
+
+/// A 64-bit perceptual hash of a bitmap, as returned by
+/// `Bitmap::perceptual_hash`.
+///
+/// Unlike the `Hash` implementation of `Bitmap`, similar-looking bitmaps
+/// produce similar hashes, so they can be compared using `distance`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Returns the number of bits that differ between the two hashes, from 0
+    /// for bitmaps that look the same to 64. Hashes are only comparable if
+    /// they were computed with the same algorithm.
+    #[inline]
+    pub fn distance(&self, other: ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+/// A connected area of matching pixels, as returned by
+/// `Bitmap::find_color_regions`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorRegion {
+    /// Bounds of the region, in points.
+    pub bounds: Rect,
+
+    /// Number of matching pixels in the region.
+    pub pixel_count: usize,
+
+    /// Mean position of the matching pixels in the region, measured from
+    /// their centers, in points.
+    pub centroid: Point,
+}
+
+/// Differences between two bitmaps, as returned by `Bitmap::diff`.
+#[derive(Clone, Debug)]
+pub struct BitmapDiff {
+    /// A faded copy of the original bitmap with changed pixels highlighted
+    /// in red.
+    pub image: Bitmap,
+
+    /// Percentage of pixels that changed, from 0 to 100.
+    pub percent_changed: f64,
+
+    /// Bounds of each changed area, in points, with overlapping or touching
+    /// areas merged.
+    pub regions: Vec<Rect>,
+}
+
+impl BitmapDiff {
+    /// Returns true if no pixels changed.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+}
+
 impl std::fmt::Debug for Bitmap {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Bitmap {{ size: {}, scale: {} }}", self.size, self.scale)
@@ -63,9 +648,14 @@ impl std::hash::Hash for Bitmap {
 impl Bitmap {
     #[inline]
     /// Creates a bitmap from the given `DynamicImage`, and scale if given
-    /// (defaults to 1).
+    /// (defaults to 1). Images in formats other than 8-bit RGBA are
+    /// converted.
     pub fn new(image: DynamicImage, scale: Option<f64>) -> Bitmap {
         let scale: f64 = scale.unwrap_or(1.0);
+        let image = match image {
+            DynamicImage::ImageRgba8(_) => image,
+            image => DynamicImage::ImageRgba8(image.into_rgba8()),
+        };
         Bitmap {
             size: Size::new(
                 f64::from(image.width()) / scale,
@@ -82,6 +672,72 @@ impl Bitmap {
         Rect::new(Point::ZERO, self.size)
     }
 
+    /// Opens the image at `path` as a bitmap.
+    ///
+    /// The scale is read from PNG metadata written by `save`. Failing that,
+    /// PNGs whose resolution is a whole multiple of 72 DPI, such as Retina
+    /// screenshots taken on macOS, are given that multiple as their scale.
+    /// Otherwise the scale defaults to 1.
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<Bitmap> {
+        let bytes = std::fs::read(path)?;
+        let image = image::load_from_memory(&bytes)?;
+        let scale = if image::guess_format(&bytes)? == ImageFormat::Png {
+            png_scale(&bytes)?
+        } else {
+            None
+        };
+        Ok(Bitmap::new(image, scale))
+    }
+
+    /// Saves the bitmap to `path` as a PNG, storing its scale in the image
+    /// metadata so that it is restored by `open`.
+    ///
+    /// The scale is written both as a text chunk and as the image
+    /// resolution, with a scale of 1 corresponding to 72 DPI.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> ImageResult<()> {
+        let file = std::fs::File::create(path)?;
+        let image = self.image.to_rgba8();
+        let mut encoder = png::Encoder::new(BufWriter::new(file), image.width(), image.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let pixels_per_meter = (self.scale * PIXELS_PER_METER).round() as u32;
+        encoder.set_pixel_dims(Some(png::PixelDimensions {
+            xppu: pixels_per_meter,
+            yppu: pixels_per_meter,
+            unit: png::Unit::Meter,
+        }));
+        encoder
+            .add_text_chunk(SCALE_KEYWORD.to_string(), self.scale.to_string())
+            .map_err(png_encoding_error)?;
+        let mut writer = encoder.write_header().map_err(png_encoding_error)?;
+        writer
+            .write_image_data(image.as_raw())
+            .map_err(png_encoding_error)?;
+        writer.finish().map_err(png_encoding_error)
+    }
+
+    /// Returns the bitmap encoded as a compact string, which can be embedded
+    /// in source files and decoded using `from_encoded_string`.
+    ///
+    /// The format is compatible with AutoPy: `b<width>,<height>,<data>`,
+    /// where `data` is the base64 encoding of the zlib-compressed pixels.
+    /// Scales other than 1 are appended as `,<scale>`. Alpha is not stored.
+    pub fn to_encoded_string(&self) -> String {
+        encoding::encode(&self.image.to_rgba8(), self.scale)
+    }
+
+    /// Decodes a bitmap from a string created by `to_encoded_string` or by
+    /// AutoPy.
+    pub fn from_encoded_string(encoded: &str) -> ImageResult<Bitmap> {
+        let (image, scale) = encoding::decode(encoded).map_err(|err| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Name("AutoPy".to_string()),
+                err,
+            ))
+        })?;
+        Ok(Bitmap::new(DynamicImage::ImageRgba8(image), Some(scale)))
+    }
+
     /// Copies image to pasteboard. Currently only supported on macOS.
     pub fn copy_to_pasteboard(&self) -> ImageResult<()> {
         self.system_copy_to_pasteboard()
@@ -105,17 +761,188 @@ impl Bitmap {
         }
     }
 
+    /// Returns a copy of the bitmap resampled to the given scale using
+    /// `filter`, e.g. to convert an asset captured on one display to the
+    /// scale of another, as returned by `screen::scale()`.
+    ///
+    /// The size in points is kept, up to rounding to whole pixels. Panics if
+    /// `scale` is not positive.
+    pub fn rescaled(&self, scale: f64, filter: ResampleFilter) -> Bitmap {
+        assert!(
+            scale.is_finite() && scale > 0.0,
+            "Scale must be positive, got {}.",
+            scale
+        );
+        if scale == self.scale {
+            return self.clone();
+        }
+
+        let factor = scale / self.scale;
+        let resampled_length = |length: u32| {
+            if length == 0 {
+                0
+            } else {
+                ((f64::from(length) * factor).round() as u32).max(1)
+            }
+        };
+        let width = resampled_length(self.image.width());
+        let height = resampled_length(self.image.height());
+        Bitmap::new(
+            self.image.resize_exact(width, height, filter.filter_type()),
+            Some(scale),
+        )
+    }
+
+    /// Returns the pixels of the bitmap as a contiguous RGBA buffer.
+    ///
+    /// This borrows the image without copying when it is stored as RGBA, as
+    /// it is after `Bitmap::new`. Images in other formats, e.g. after `image`
+    /// was replaced by a grayscale copy, are converted first.
+    pub fn pixels(&self) -> PixelView<'_> {
+        let (width, height) = (self.image.width(), self.image.height());
+        let data = match self.image.as_rgba8() {
+            Some(image) => Cow::Borrowed(image.as_raw().as_slice()),
+            None => Cow::Owned(self.image.to_rgba8().into_raw()),
+        };
+        PixelView {
+            data,
+            width,
+            height,
+            stride: width as usize * 4,
+        }
+    }
+
     // Returns color of underlying image at the given point.
     pub fn get_pixel(&self, point: Point) -> Rgba<u8> {
         let point = point.scaled(self.multiplier()).round();
-        self.image.get_pixel(point.x as u32, point.y as u32)
+        match self.image.as_rgba8() {
+            Some(image) => *image.get_pixel(point.x as u32, point.y as u32),
+            None => self.image.get_pixel(point.x as u32, point.y as u32),
+        }
     }
 
     /// Returns true if bitmap is equal to needle with the given tolerance.
     pub fn bitmap_eq(&self, needle: &Bitmap, tolerance: Option<f64>) -> bool {
+        self.bitmap_eq_with_metric(needle, ColorMetric::Rgb, tolerance)
+    }
+
+    /// Returns true if bitmap is equal to needle with the given tolerance,
+    /// comparing colors using `metric`.
+    pub fn bitmap_eq_with_metric(
+        &self,
+        needle: &Bitmap,
+        metric: ColorMetric,
+        tolerance: Option<f64>,
+    ) -> bool {
         self.size == needle.size
             && self.scale == needle.scale
-            && self.is_needle_at(Point::ZERO, needle, tolerance)
+            && self.is_needle_at(Point::ZERO, needle, tolerance, metric)
+    }
+
+    /// Returns a perceptual hash of the bitmap using the given algorithm.
+    /// Transparency is ignored.
+    pub fn perceptual_hash(&self, algorithm: HashAlgorithm) -> ImageHash {
+        let thumbnail = |width, height| {
+            self.image
+                .resize_exact(width, height, FilterType::Triangle)
+                .to_luma8()
+                .pixels()
+                .map(|pixel| f64::from(pixel[0]))
+                .collect::<Vec<f64>>()
+        };
+        let bits: Vec<bool> = match algorithm {
+            HashAlgorithm::Average => {
+                let values = thumbnail(8, 8);
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                values.iter().map(|&value| value > mean).collect()
+            }
+            HashAlgorithm::Difference => {
+                let values = thumbnail(9, 8);
+                values
+                    .chunks(9)
+                    .flat_map(|row| row.windows(2).map(|pair| pair[0] > pair[1]))
+                    .collect()
+            }
+            HashAlgorithm::Perceptual => {
+                let values = thumbnail(32, 32);
+                let coefficients = low_frequencies(&values, 32, 8);
+                let mut sorted = coefficients.clone();
+                sorted.sort_by(f64::total_cmp);
+                let median = (sorted[31] + sorted[32]) / 2.0;
+                coefficients.iter().map(|&value| value > median).collect()
+            }
+        };
+        ImageHash(bits.iter().fold(0, |hash, &bit| (hash << 1) | u64::from(bit)))
+    }
+
+    /// Returns the Hamming distance between the perceptual hashes of the two
+    /// bitmaps, from 0 for bitmaps that look the same to 64.
+    pub fn perceptual_distance(&self, other: &Bitmap, algorithm: HashAlgorithm) -> u32 {
+        self.perceptual_hash(algorithm)
+            .distance(other.perceptual_hash(algorithm))
+    }
+
+    /// Compares the bitmap to `other` pixel by pixel, returning which areas
+    /// changed. Pixels are considered changed if their colors do not match
+    /// within `tolerance`, as accepted by `find_color`.
+    ///
+    /// If the bitmaps differ in size, pixels that lie outside either one are
+    /// considered changed.
+    pub fn diff(&self, other: &Bitmap, tolerance: Option<f64>) -> BitmapDiff {
+        let tolerance = tolerance.unwrap_or(0.0);
+        let width = self.image.width().max(other.image.width());
+        let height = self.image.height().max(other.image.height());
+        let pixel = |bitmap: &Bitmap, x, y| {
+            if bitmap.image.in_bounds(x, y) {
+                Some(bitmap.image.get_pixel(x, y))
+            } else {
+                None
+            }
+        };
+
+        let mut changed = vec![false; (width * height) as usize];
+        let mut image = RgbaImage::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let (original, updated) = (pixel(self, x, y), pixel(other, x, y));
+                let is_changed = match (original, updated) {
+                    (Some(c1), Some(c2)) => !colors_match(c1, c2, tolerance),
+                    _ => true,
+                };
+                changed[(y * width + x) as usize] = is_changed;
+                let highlighted = if is_changed {
+                    Rgba([255, 0, 0, 255])
+                } else {
+                    // Fade unchanged pixels towards white so highlights stand
+                    // out.
+                    let luma = original.map_or(255, |color| color.to_luma()[0]);
+                    let faded = (u16::from(luma) / 3 + 170) as u8;
+                    Rgba([faded, faded, faded, 255])
+                };
+                image.put_pixel(x, y, highlighted);
+            }
+        }
+
+        let count = changed.iter().filter(|&&is_changed| is_changed).count();
+        let mut regions: Vec<Rect> = merged_components(width, height, &changed)
+            .iter()
+            .map(|component| component.bounds(self.scale))
+            .collect();
+        regions.sort_by(|a, b| {
+            (a.origin.y, a.origin.x)
+                .partial_cmp(&(b.origin.y, b.origin.x))
+                .unwrap()
+        });
+
+        BitmapDiff {
+            image: Bitmap::new(DynamicImage::ImageRgba8(image), Some(self.scale)),
+            percent_changed: if changed.is_empty() {
+                0.0
+            } else {
+                100.0 * count as f64 / changed.len() as f64
+            },
+            regions,
+        }
     }
 
     /// Attempts to find `color` inside `rect` in `bmp` from the given
@@ -133,9 +960,21 @@ impl Bitmap {
         start_point: Option<Point>,
     ) -> Option<Point> {
         let tolerance = tolerance.unwrap_or(0.0);
-        self.find(rect, start_point, |point| {
-            colors_match(needle, self.get_pixel(point), tolerance)
-        })
+        self.find_pixel(rect, start_point, |color| colors_match(needle, color, tolerance))
+    }
+
+    /// Attempts to find `color` as with `find_color`, comparing colors using
+    /// `metric`.
+    pub fn find_color_with_metric(
+        &self,
+        needle: Rgba<u8>,
+        metric: ColorMetric,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Point> {
+        let tolerance = tolerance.unwrap_or(0.0);
+        self.find_pixel(rect, start_point, |color| metric.matches(needle, color, tolerance))
     }
 
     /// Returns list of all coordinates inside `rect` in `bmp` matching
@@ -155,10 +994,10 @@ impl Bitmap {
             let mut matched = |point| {
                 points.push(point);
             };
-            self.find_all(
+            self.find_all_pixels(
                 rect,
                 start_point,
-                &(|point| colors_match(needle, self.get_pixel(point), tolerance)),
+                &(|color| colors_match(needle, color, tolerance)),
                 &mut matched,
             );
         }
@@ -182,102 +1021,883 @@ impl Bitmap {
             let mut matched = |_| {
                 count += 1;
             };
-            self.find_all(
+            self.find_all_pixels(
                 rect,
                 start_point,
-                &(|point| colors_match(needle, self.get_pixel(point), tolerance)),
+                &(|color| colors_match(needle, color, tolerance)),
                 &mut matched,
             );
         }
         count
     }
 
-    /// Attempts to find `needle` inside `rect` in `bmp` from the given
-    /// `start_point`. Returns coordinates if found, or `None` if not. If
-    /// `rect` is `None`, `bmp.bounds()` is used instead. If `start_point` is
-    /// `None`, the origin of `rect` is used.
-    ///
-    /// Tolerance is defined as a float in the range from 0 to 1, where 0 is
-    /// an exact match and 1 matches anything.
-    pub fn find_bitmap(
+    /// Attempts to find a color within `range` inside `rect` in `bmp` from
+    /// the given `start_point`. Returns coordinates if found, or `None` if
+    /// not. If `rect` is `None`, `bmp.bounds()` is used instead. If
+    /// `start_point` is `None`, the origin of `rect` is used.
+    pub fn find_color_in_range(
         &self,
-        needle: &Bitmap,
-        tolerance: Option<f64>,
+        range: ColorRange,
         rect: Option<Rect>,
         start_point: Option<Point>,
     ) -> Option<Point> {
-        if self.is_needle_oversized(needle) {
-            return None;
-        }
-
-        self.find(rect, start_point, |pt| {
-            self.is_needle_at(pt, needle, tolerance)
-        })
+        self.find_pixel(rect, start_point, |color| range.contains(color))
     }
 
-    /// Returns list of all coordinates inside `rect` in `bmp` matching
-    /// `needle` from the given `start_point`. If `rect` is `None`,
-    /// `bmp.bounds` is used instead. If `start_point` is `None`, the origin
+    /// Returns list of all coordinates inside `rect` in `bmp` with a color
+    /// within `range` from the given `start_point`. If `rect` is `None`,
+    /// `bmp.bounds()` is used instead. If `start_point` is `None`, the origin
     /// of `rect` is used.
-    pub fn find_every_bitmap(
+    pub fn find_every_color_in_range(
         &self,
-        needle: &Bitmap,
-        tolerance: Option<f64>,
+        range: ColorRange,
         rect: Option<Rect>,
         start_point: Option<Point>,
     ) -> Vec<Point> {
-        if self.is_needle_oversized(needle) {
-            return Vec::new();
-        }
-
         let mut points: Vec<Point> = Vec::new();
         {
             let mut matched = |point| {
                 points.push(point);
             };
-            self.find_all(
+            self.find_all_pixels(
                 rect,
                 start_point,
-                &(|pt| self.is_needle_at(pt, needle, tolerance)),
+                &(|color| range.contains(color)),
                 &mut matched,
             );
         }
         points
     }
 
-    /// Returns count of occurrences of `needle` in `bmp`. Functionally equivalent to:
-    ///
-    /// ```rust,ignore
-    /// find_every_bitmap(color, tolerance, rect, start_point).count()
-    /// ```
-    pub fn count_of_bitmap(
+    /// Returns count of colors within `range` inside `rect` in `bmp` from the
+    /// given `start_point`. If `rect` is `None`, `bmp.bounds()` is used
+    /// instead. If `start_point` is `None`, the origin of `rect` is used.
+    pub fn count_of_color_in_range(
         &self,
-        needle: &Bitmap,
-        tolerance: Option<f64>,
+        range: ColorRange,
         rect: Option<Rect>,
         start_point: Option<Point>,
     ) -> u64 {
-        if self.is_needle_oversized(needle) {
-            return 0;
-        }
-
         let mut count: u64 = 0;
         {
             let mut matched = |_| {
                 count += 1;
             };
-            self.find_all(
+            self.find_all_pixels(
                 rect,
                 start_point,
-                &(|pt| self.is_needle_at(pt, needle, tolerance)),
+                &(|color| range.contains(color)),
                 &mut matched,
             );
         }
         count
     }
 
-    #[inline]
-    fn multiplier(&self) -> f64 {
+    /// Returns each connected region of pixels inside `rect` in `bmp`
+    /// matching `color`, ordered by their top-most, then left-most pixel.
+    /// Pixels are connected if they touch horizontally, vertically or
+    /// diagonally. If `rect` is `None`, `bmp.bounds()` is used instead.
+    ///
+    /// See `find_color` for how tolerance is defined.
+    pub fn find_color_regions(
+        &self,
+        needle: Rgba<u8>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+    ) -> Vec<ColorRegion> {
+        let rect = rect.unwrap_or_else(|| self.bounds());
+        if !self.bounds().is_rect_visible(rect) {
+            panic!(
+                "invalid rect: {} outside of image bounds ({})",
+                rect,
+                self.bounds()
+            );
+        }
+
+        let tolerance = tolerance.unwrap_or(0.0);
+        let pixel_rect = rect.scaled(self.scale).round();
+        let (origin_x, origin_y) = (pixel_rect.origin.x as u32, pixel_rect.origin.y as u32);
+        let width = (pixel_rect.size.width as u32).min(self.image.width() - origin_x);
+        let height = (pixel_rect.size.height as u32).min(self.image.height() - origin_y);
+        let mut mask = Vec::with_capacity((width * height) as usize);
+        for row in self.pixels().view(origin_x, origin_y, width, height).rows() {
+            mask.extend(
+                row.chunks_exact(4)
+                    .map(|pixel| colors_match(needle, *Rgba::from_slice(pixel), tolerance)),
+            );
+        }
+
+        let offset = Point::from_pixel(f64::from(origin_x), f64::from(origin_y), self.scale);
+        pixel_components(width, height, &mask)
+            .iter()
+            .map(|component| {
+                let bounds = component.bounds(self.scale);
+                let centroid = component.centroid(self.scale);
+                ColorRegion {
+                    bounds: Rect::new(
+                        Point::new(bounds.origin.x + offset.x, bounds.origin.y + offset.y),
+                        bounds.size,
+                    ),
+                    pixel_count: component.count,
+                    centroid: Point::new(centroid.x + offset.x, centroid.y + offset.y),
+                }
+            })
+            .collect()
+    }
+
+    /// Attempts to find `needle` inside `rect` in `bmp` from the given
+    /// `start_point`. Returns coordinates if found, or `None` if not. If
+    /// `rect` is `None`, `bmp.bounds()` is used instead. If `start_point` is
+    /// `None`, the origin of `rect` is used.
+    ///
+    /// Tolerance is defined as a float in the range from 0 to 1, where 0 is
+    /// an exact match and 1 matches anything.
+    pub fn find_bitmap(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Point> {
+        self.find_weighted_bitmap(needle, None, tolerance, rect, start_point)
+    }
+
+    /// Attempts to find `needle` as with `find_bitmap`, comparing colors
+    /// using `metric`.
+    pub fn find_bitmap_with_metric(
+        &self,
+        needle: &Bitmap,
+        metric: ColorMetric,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Point> {
+        if self.is_needle_oversized(needle) {
+            return None;
+        }
+
+        let (haystack_grid, needle_grid) = self.search_grids(needle);
+        let search = TemplateSearch::with_metric(
+            &haystack_grid,
+            &needle_grid,
+            tolerance.unwrap_or(0.0),
+            None,
+            metric,
+        );
+        self.find(&search, rect, start_point)
+    }
+
+    /// Returns list of all coordinates inside `rect` in `bmp` matching
+    /// `needle` from the given `start_point`. If `rect` is `None`,
+    /// `bmp.bounds` is used instead. If `start_point` is `None`, the origin
+    /// of `rect` is used.
+    pub fn find_every_bitmap(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Vec<Point> {
+        let mut points: Vec<Point> = Vec::new();
+        self.find_every_weighted_bitmap(needle, None, tolerance, rect, start_point, &mut |point| {
+            points.push(point);
+        });
+        points
+    }
+
+    /// Returns count of occurrences of `needle` in `bmp`. Functionally equivalent to:
+    ///
+    /// ```rust,ignore
+    /// find_every_bitmap(color, tolerance, rect, start_point).count()
+    /// ```
+    pub fn count_of_bitmap(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> u64 {
+        let mut count: u64 = 0;
+        self.find_every_weighted_bitmap(needle, None, tolerance, rect, start_point, &mut |_| {
+            count += 1;
+        });
+        count
+    }
+
+    /// Attempts to find `needle` inside `rect` in `bmp` from the given
+    /// `start_point`. Returns the match if found, or `None` if not. This is
+    /// the same as `find_bitmap`, but also returns the bounds, score and
+    /// scale of the match.
+    ///
+    /// The score is one minus the mean distance between the colors of the
+    /// needle and the match, in the same units as tolerance.
+    pub fn find_bitmap_match(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Match> {
+        if self.is_needle_oversized(needle) {
+            return None;
+        }
+
+        let (haystack_grid, needle_grid) = self.search_grids(needle);
+        let search =
+            TemplateSearch::new(&haystack_grid, &needle_grid, tolerance.unwrap_or(0.0), None);
+        self.find(&search, rect, start_point)
+            .map(|point| self.template_match(point, needle, &search))
+    }
+
+    /// Returns list of all matches inside `rect` in `bmp` for `needle` from
+    /// the given `start_point`. This is the same as `find_every_bitmap`, but
+    /// also returns the bounds, score and scale of each match.
+    pub fn find_every_bitmap_match(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Vec<Match> {
+        if self.is_needle_oversized(needle) {
+            return Vec::new();
+        }
+
+        let (haystack_grid, needle_grid) = self.search_grids(needle);
+        let search =
+            TemplateSearch::new(&haystack_grid, &needle_grid, tolerance.unwrap_or(0.0), None);
+        let mut matches: Vec<Match> = Vec::new();
+        {
+            let mut matched = |point| {
+                matches.push(self.template_match(point, needle, &search));
+            };
+            self.find_all(&search, rect, start_point, &mut matched);
+        }
+        matches
+    }
+
+    /// Attempts to find `needle` inside `rect` in `bmp` from the given
+    /// `start_point`, comparing pixels as described by `mode`. Returns
+    /// coordinates if found, or `None` if not. `MatchMode::Rgb` is the same
+    /// as `find_bitmap`.
+    ///
+    /// Tolerance is applied to the converted pixels, as returned by
+    /// `converted`.
+    pub fn find_bitmap_with_mode(
+        &self,
+        needle: &Bitmap,
+        mode: MatchMode,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Point> {
+        match mode {
+            MatchMode::Rgb => self.find_bitmap(needle, tolerance, rect, start_point),
+            MatchMode::Grayscale => {
+                self.converted(mode)
+                    .find_bitmap(&needle.converted(mode), tolerance, rect, start_point)
+            }
+            MatchMode::Edges => {
+                let needle = needle.converted(mode);
+                self.converted(mode).find_masked_bitmap(
+                    &needle,
+                    Some(&needle.border_mask()),
+                    tolerance,
+                    rect,
+                    start_point,
+                )
+            }
+        }
+    }
+
+    /// Returns list of all coordinates inside `rect` in `bmp` matching
+    /// `needle` from the given `start_point`, comparing pixels as described
+    /// by `mode`. See `find_bitmap_with_mode`.
+    pub fn find_every_bitmap_with_mode(
+        &self,
+        needle: &Bitmap,
+        mode: MatchMode,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Vec<Point> {
+        match mode {
+            MatchMode::Rgb => self.find_every_bitmap(needle, tolerance, rect, start_point),
+            MatchMode::Grayscale => self.converted(mode).find_every_bitmap(
+                &needle.converted(mode),
+                tolerance,
+                rect,
+                start_point,
+            ),
+            MatchMode::Edges => {
+                let needle = needle.converted(mode);
+                self.converted(mode).find_every_masked_bitmap(
+                    &needle,
+                    Some(&needle.border_mask()),
+                    tolerance,
+                    rect,
+                    start_point,
+                )
+            }
+        }
+    }
+
+    /// Returns a copy of the bitmap with its pixels converted as they are
+    /// compared by `mode`. Converting a haystack once up front avoids
+    /// repeating the conversion when searching it for many needles.
+    pub fn converted(&self, mode: MatchMode) -> Bitmap {
+        let rgba = self.image.to_rgba8();
+        let image = match mode {
+            MatchMode::Rgb => rgba,
+            MatchMode::Grayscale => RgbaImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+                let pixel = rgba.get_pixel(x, y);
+                let luma = pixel.to_luma()[0];
+                Rgba([luma, luma, luma, pixel[3]])
+            }),
+            MatchMode::Edges => {
+                let (width, height) = rgba.dimensions();
+                let luma = |x: i64, y: i64| {
+                    let x = x.clamp(0, i64::from(width) - 1) as u32;
+                    let y = y.clamp(0, i64::from(height) - 1) as u32;
+                    f64::from(rgba.get_pixel(x, y).to_luma()[0])
+                };
+                RgbaImage::from_fn(width, height, |x, y| {
+                    let (x, y) = (i64::from(x), i64::from(y));
+                    let gx = luma(x + 1, y - 1) + 2.0 * luma(x + 1, y) + luma(x + 1, y + 1)
+                        - luma(x - 1, y - 1)
+                        - 2.0 * luma(x - 1, y)
+                        - luma(x - 1, y + 1);
+                    let gy = luma(x - 1, y + 1) + 2.0 * luma(x, y + 1) + luma(x + 1, y + 1)
+                        - luma(x - 1, y - 1)
+                        - 2.0 * luma(x, y - 1)
+                        - luma(x + 1, y - 1);
+                    let magnitude = (gx.hypot(gy) / 4.0).round().min(255.0) as u8;
+                    Rgba([magnitude, magnitude, magnitude, 255])
+                })
+            }
+        };
+        Bitmap::new(DynamicImage::ImageRgba8(image), Some(self.scale))
+    }
+
+    /// Returns a mask of the same size as the bitmap that hides its
+    /// outermost pixels.
+    fn border_mask(&self) -> Bitmap {
+        let (width, height) = self.image.dimensions();
+        let mask = RgbaImage::from_fn(width, height, |x, y| {
+            let inside = x > 0 && y > 0 && x + 1 < width && y + 1 < height;
+            let v = if inside { 255 } else { 0 };
+            Rgba([v, v, v, 255])
+        });
+        Bitmap::new(DynamicImage::ImageRgba8(mask), Some(self.scale))
+    }
+
+    /// Returns list of distinct matches inside `rect` in `bmp` for `needle`
+    /// from the given `start_point`, keeping only the best match of each
+    /// group that `overlap` considers the same occurrence. With a non-zero
+    /// tolerance, `find_every_bitmap` returns every offset around an
+    /// occurrence that is within tolerance; this returns one per occurrence.
+    pub fn find_every_distinct_bitmap(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        overlap: Overlap,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Vec<Match> {
+        let matches = self.find_every_bitmap_match(needle, tolerance, rect, start_point);
+        suppress_overlapping_matches(&matches, overlap)
+    }
+
+    /// Returns count of distinct occurrences of `needle` in `bmp`.
+    /// Functionally equivalent to:
+    ///
+    /// ```rust,ignore
+    /// find_every_distinct_bitmap(needle, tolerance, overlap, rect, start_point).count()
+    /// ```
+    pub fn count_of_distinct_bitmap(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        overlap: Overlap,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> u64 {
+        self.find_every_distinct_bitmap(needle, tolerance, overlap, rect, start_point)
+            .len() as u64
+    }
+
+    /// Attempts to find `needle` inside `rect` in `bmp` from the given
+    /// `start_point`, ignoring the parts of the needle hidden by `mask`.
+    /// Returns coordinates if found, or `None` if not. If `rect` is `None`,
+    /// `bmp.bounds()` is used instead. If `start_point` is `None`, the origin
+    /// of `rect` is used.
+    ///
+    /// Each pixel of the needle is weighted by the brightness and opacity of
+    /// the same pixel in `mask`, or by its own opacity if `mask` is `None`.
+    /// Pixels with a weight of zero match anything, which allows searching
+    /// for non-rectangular needles regardless of their background. Partly
+    /// transparent pixels, e.g. anti-aliased edges, appear blended with the
+    /// background, so their tolerance is loosened by one minus their weight,
+    /// even with the default tolerance of 0. Only the RGB channels of the
+    /// needle are compared.
+    ///
+    /// Panics if `mask` is not the same size as `needle`.
+    pub fn find_masked_bitmap(
+        &self,
+        needle: &Bitmap,
+        mask: Option<&Bitmap>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Point> {
+        let weights = needle.mask_weights(mask);
+        self.find_weighted_bitmap(needle, Some(&weights), tolerance, rect, start_point)
+    }
+
+    /// Returns list of all coordinates inside `rect` in `bmp` matching
+    /// `needle` hidden by `mask` from the given `start_point`. See
+    /// `find_masked_bitmap` for how masks are applied.
+    pub fn find_every_masked_bitmap(
+        &self,
+        needle: &Bitmap,
+        mask: Option<&Bitmap>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Vec<Point> {
+        let weights = needle.mask_weights(mask);
+        let mut points: Vec<Point> = Vec::new();
+        self.find_every_weighted_bitmap(
+            needle,
+            Some(&weights),
+            tolerance,
+            rect,
+            start_point,
+            &mut |point| {
+                points.push(point);
+            },
+        );
+        points
+    }
+
+    /// Returns count of occurrences of `needle` hidden by `mask` in `bmp`.
+    /// Functionally equivalent to:
+    ///
+    /// ```rust,ignore
+    /// find_every_masked_bitmap(needle, mask, tolerance, rect, start_point).count()
+    /// ```
+    pub fn count_of_masked_bitmap(
+        &self,
+        needle: &Bitmap,
+        mask: Option<&Bitmap>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> u64 {
+        let weights = needle.mask_weights(mask);
+        let mut count: u64 = 0;
+        self.find_every_weighted_bitmap(
+            needle,
+            Some(&weights),
+            tolerance,
+            rect,
+            start_point,
+            &mut |_| {
+                count += 1;
+            },
+        );
+        count
+    }
+
+    /// Returns the coordinates of the closest match for `needle` inside
+    /// `rect` in `bmp`, along with its score, or `None` if the needle does
+    /// not fit. If `rect` is `None`, `bmp.bounds()` is used instead.
+    ///
+    /// Unlike `find_bitmap`, matching is done using normalized
+    /// cross-correlation, so small differences in brightness, contrast or
+    /// anti-aliasing lower the score rather than rejecting the match. Score
+    /// is defined as a float in the range from 0 to 1, where 1 is a perfect
+    /// match.
+    pub fn find_best_bitmap(&self, needle: &Bitmap, rect: Option<Rect>) -> Option<Match> {
+        let (haystack, origin) = self.correlation_planes(rect);
+        self.best_candidate(&haystack, origin, needle)
+    }
+
+    /// Returns list of all matches inside `rect` in `bmp` where `needle`
+    /// scores at least `min_score`, ordered from best to worst. If `rect` is
+    /// `None`, `bmp.bounds()` is used instead.
+    ///
+    /// See `find_best_bitmap` for how scores are defined.
+    pub fn find_every_scored_bitmap(
+        &self,
+        needle: &Bitmap,
+        min_score: f64,
+        rect: Option<Rect>,
+    ) -> Vec<Match> {
+        let (haystack, origin) = self.correlation_planes(rect);
+        let mut matches: Vec<Match> = Vec::new();
+        self.scored_candidates(&haystack, origin, needle, &mut |candidate| {
+            if candidate.score >= min_score {
+                matches.push(candidate);
+            }
+        });
+        matches.sort_by(match_order);
+        matches
+    }
+
+    /// Attempts to find `needle` inside `rect` in `bmp` after resampling it
+    /// by each factor in `scales`, so that needles captured on a display
+    /// with a different scale can still be found. Returns the best match
+    /// scoring at least `min_score` along with the factor it matched at, or
+    /// `None` if there is none. If `rect` is `None`, `bmp.bounds()` is used
+    /// instead.
+    ///
+    /// Factors are relative to the needle's pixel size, e.g. a needle
+    /// captured at a scale of 1 matches a haystack with a scale of 2 at a
+    /// factor of 2. They are tried starting from the one closest to the
+    /// ratio between the two scales, and the search stops at the first
+    /// factor that matches with a score of at least 0.99. The scale of the
+    /// returned match is that of the resampled needle, which is always that
+    /// of `bmp`. See `find_best_bitmap` for how scores are defined.
+    pub fn find_bitmap_at_scales(
+        &self,
+        needle: &Bitmap,
+        scales: ScaleRange,
+        min_score: f64,
+        rect: Option<Rect>,
+    ) -> Option<(Match, f64)> {
+        let (haystack, origin) = self.correlation_planes(rect);
+        let expected = self.scale / needle.scale;
+        let mut factors = scales.factors();
+        factors.sort_by(|a, b| {
+            (a / expected)
+                .ln()
+                .abs()
+                .total_cmp(&(b / expected).ln().abs())
+        });
+
+        let mut best: Option<(Match, f64)> = None;
+        for factor in factors {
+            if (f64::from(needle.image.width()) * factor).round() < 1.0
+                || (f64::from(needle.image.height()) * factor).round() < 1.0
+            {
+                continue;
+            }
+
+            // Searches compare pixels, so the resampled needle takes on the
+            // scale of the haystack.
+            let resampled = needle.rescaled(needle.scale * factor, ResampleFilter::Bilinear);
+            let resampled = Bitmap::new(resampled.image, Some(self.scale));
+            if let Some(candidate) = self.best_candidate(&haystack, origin, &resampled)
+                && candidate.score >= min_score
+                && best.is_none_or(|(best, _)| candidate.score > best.score)
+            {
+                best = Some((candidate, factor));
+                if candidate.score >= CONFIDENT_SCORE {
+                    break;
+                }
+            }
+        }
+        best
+    }
+
+    /// Attempts to find `needle` inside `rect` in `bmp` by matching corner
+    /// features between the two, so that needles which are scaled, slightly
+    /// rotated or partly covered can still be found. Returns `None` if fewer
+    /// than `min_inliers` features agree on its position. If `rect` is
+    /// `None`, `bmp.bounds()` is used instead. If `min_inliers` is `None`,
+    /// 12 is used instead.
+    ///
+    /// This needs needles with enough texture to have distinct corners, such
+    /// as icons or text, and is much slower than `find_bitmap`.
+    pub fn find_features(
+        &self,
+        needle: &Bitmap,
+        min_inliers: Option<usize>,
+        rect: Option<Rect>,
+    ) -> Option<FeatureMatch> {
+        let rect = rect.unwrap_or_else(|| self.bounds());
+        if !self.bounds().is_rect_visible(rect) {
+            panic!(
+                "invalid rect: {} outside of image bounds ({})",
+                rect,
+                self.bounds()
+            );
+        }
+
+        let pixel_rect = rect.scaled(self.scale).round();
+        let haystack = self.image.crop_imm(
+            pixel_rect.origin.x as u32,
+            pixel_rect.origin.y as u32,
+            pixel_rect.size.width as u32,
+            pixel_rect.size.height as u32,
+        );
+        features::locate(
+            &haystack,
+            self.scale,
+            pixel_rect.origin.scaled(1.0 / self.scale),
+            &needle.image,
+            needle.scale,
+            min_inliers.unwrap_or(12),
+        )
+    }
+
+    /// Fills `rect` with the given color, blending it with the bitmap if the
+    /// color is translucent. Parts outside the bitmap are clipped.
+    pub fn fill_rect(&mut self, rect: Rect, color: Rgba<u8>) {
+        let rect = rect.scaled(self.scale).round();
+        self.blend_pixels(rect, color);
+    }
+
+    /// Draws the outline of `rect` in the given color, with lines `thickness`
+    /// points wide drawn just inside it (defaults to 1). Parts outside the
+    /// bitmap are clipped.
+    pub fn draw_rect(&mut self, rect: Rect, color: Rgba<u8>, thickness: Option<f64>) {
+        let rect = rect.scaled(self.scale).round();
+        let thickness = (thickness.unwrap_or(1.0) * self.scale).round().max(1.0);
+        let (min_x, min_y, max_x, max_y) = (rect.origin.x, rect.origin.y, rect.max_x(), rect.max_y());
+        let top = (min_y + thickness).min(max_y);
+        let bottom = (max_y - thickness).max(top);
+        let left = (min_x + thickness).min(max_x);
+        let right = (max_x - thickness).max(left);
+        self.blend_pixels(pixel_rect(min_x, min_y, max_x, top), color);
+        self.blend_pixels(pixel_rect(min_x, bottom, max_x, max_y), color);
+        self.blend_pixels(pixel_rect(min_x, top, left, bottom), color);
+        self.blend_pixels(pixel_rect(right, top, max_x, bottom), color);
+    }
+
+    /// Draws a crosshair one point thick centered on the pixel at `point`,
+    /// with arms extending `size` points from the center (defaults to 6).
+    pub fn draw_crosshair(&mut self, point: Point, color: Rgba<u8>, size: Option<f64>) {
+        let center = point.scaled(self.scale);
+        let (x, y) = (center.x.floor(), center.y.floor());
+        let thickness = self.scale.round().max(1.0);
+        let offset = (thickness / 2.0).floor();
+        let arm = (size.unwrap_or(6.0) * self.scale).round();
+        let (line_min, line_max) = (-offset, thickness - offset);
+        self.blend_pixels(
+            pixel_rect(x - arm, y + line_min, x + arm + 1.0, y + line_max),
+            color,
+        );
+        self.blend_pixels(
+            pixel_rect(x + line_min, y - arm, x + line_max, y + line_min),
+            color,
+        );
+        self.blend_pixels(
+            pixel_rect(x + line_min, y + line_max, x + line_max, y + arm + 1.0),
+            color,
+        );
+    }
+
+    /// Draws `text` with its top-left corner at `point`, on a background of
+    /// the given color if any, and returns the bounds of the label in points.
+    ///
+    /// Text is drawn in a built-in 5x7 pixel font, at one font pixel per
+    /// point. It covers digits, uppercase letters and common punctuation;
+    /// lowercase letters are drawn as uppercase, and other characters as
+    /// empty boxes. Lines are separated by `\n`.
+    pub fn draw_label(
+        &mut self,
+        point: Point,
+        text: &str,
+        color: Rgba<u8>,
+        background: Option<Rgba<u8>>,
+    ) -> Rect {
+        // Labels are padded by one font pixel on each side so that text
+        // stays legible against the background.
+        let lines: Vec<&str> = text.split('\n').collect();
+        let line_height = font::GLYPH_HEIGHT + 2;
+        let width = lines.iter().map(|line| font::text_width(line)).max().unwrap_or(0) + 2;
+        let height = lines.len() as u32 * line_height;
+        let bounds = Rect::new(point, Size::new(f64::from(width), f64::from(height)));
+        if let Some(background) = background {
+            self.fill_rect(bounds, background);
+        }
+
+        let unit = self.scale.round().max(1.0);
+        let origin = point.scaled(self.scale).round();
+        for (row, line) in lines.iter().enumerate() {
+            let top = origin.y + f64::from(row as u32 * line_height + 1) * unit;
+            for (column, c) in line.chars().enumerate() {
+                let glyph = font::glyph(c);
+                let left = origin.x
+                    + f64::from(column as u32 * (font::GLYPH_WIDTH + font::GLYPH_SPACING) + 1)
+                        * unit;
+                for glyph_y in 0..font::GLYPH_HEIGHT {
+                    for glyph_x in 0..font::GLYPH_WIDTH {
+                        if font::is_set(&glyph, glyph_x, glyph_y) {
+                            let x = left + f64::from(glyph_x) * unit;
+                            let y = top + f64::from(glyph_y) * unit;
+                            self.blend_pixels(pixel_rect(x, y, x + unit, y + unit), color);
+                        }
+                    }
+                }
+            }
+        }
+        bounds
+    }
+
+    /// Returns a copy of the bitmap annotated with the parameters and
+    /// results of a search, for debugging searches that fail or find the
+    /// wrong spot.
+    ///
+    /// The search `rect` is outlined in blue and `start_point` is marked with
+    /// an orange crosshair, defaulting as described in `find_bitmap`. Each of
+    /// `matches` is outlined in green and labeled with its position in the
+    /// list, starting from 1.
+    pub fn annotated(
+        &self,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+        matches: &[Rect],
+    ) -> Bitmap {
+        let mut annotated = self.clone();
+        annotated.draw_rect(rect.unwrap_or_else(|| self.bounds()), SEARCH_RECT_COLOR, None);
+        annotated.draw_crosshair(
+            start_point.unwrap_or(self.bounds().origin),
+            START_POINT_COLOR,
+            None,
+        );
+        for (idx, rect) in matches.iter().enumerate() {
+            annotated.draw_rect(*rect, MATCH_COLOR, Some(2.0));
+            let label = (idx + 1).to_string();
+            // Place labels above matches where there is room, so that they
+            // do not cover the matched area.
+            let label_height = f64::from(font::GLYPH_HEIGHT + 2);
+            let label_y = if rect.origin.y >= label_height {
+                rect.origin.y - label_height
+            } else {
+                rect.origin.y
+            };
+            annotated.draw_label(
+                Point::new(rect.origin.x, label_y),
+                &label,
+                Rgba([255, 255, 255, 255]),
+                Some(MATCH_COLOR),
+            );
+        }
+        annotated
+    }
+
+    /// Searches for `needle` as with `find_bitmap`, and saves a copy of the
+    /// bitmap annotated with the search rect, start point and every match to
+    /// `path` as described in `annotated`.
+    ///
+    /// The first match is the one returned by `find_bitmap`.
+    pub fn find_bitmap_debug<P: AsRef<Path>>(
+        &self,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+        path: P,
+    ) -> ImageResult<Option<Point>> {
+        let matches: Vec<Rect> = self
+            .find_every_bitmap_match(needle, tolerance, rect, start_point)
+            .iter()
+            .map(|found| found.rect)
+            .collect();
+        self.annotated(rect, start_point, &matches).save(path)?;
+        Ok(matches.first().map(|found| found.origin))
+    }
+
+    /// Blends `color` into the pixels of `rect`, given in pixels, clipping it
+    /// to the bitmap.
+    fn blend_pixels(&mut self, rect: Rect, color: Rgba<u8>) {
+        if self.image.as_rgba8().is_none() {
+            self.image = DynamicImage::ImageRgba8(self.image.to_rgba8());
+        }
+        let image = self
+            .image
+            .as_mut_rgba8()
+            .expect("image was just converted to RGBA");
+        let (width, height) = image.dimensions();
+        let clamp = |value: f64, limit: u32| value.clamp(0.0, f64::from(limit)) as u32;
+        for y in clamp(rect.origin.y, height)..clamp(rect.max_y(), height) {
+            for x in clamp(rect.origin.x, width)..clamp(rect.max_x(), width) {
+                blend(image.get_pixel_mut(x, y), color);
+            }
+        }
+    }
+
+    /// Returns the pixels inside `rect` prepared for scoring candidates,
+    /// along with their origin in pixels. If `rect` is `None`, `bmp.bounds()`
+    /// is used instead.
+    fn correlation_planes(&self, rect: Option<Rect>) -> (Planes, (u32, u32)) {
+        let rect = rect.unwrap_or_else(|| self.bounds());
+        if !self.bounds().is_rect_visible(rect) {
+            panic!(
+                "invalid rect: {} outside of image bounds ({})",
+                rect,
+                self.bounds()
+            );
+        }
+
+        let pixel_rect = rect.scaled(self.scale).round();
+        let (origin_x, origin_y) = (pixel_rect.origin.x as u32, pixel_rect.origin.y as u32);
+        let width = (pixel_rect.size.width as u32).min(self.image.width() - origin_x);
+        let height = (pixel_rect.size.height as u32).min(self.image.height() - origin_y);
+        let pixels = self.pixels();
+        let planes = Planes::from_pixels(&pixels.view(origin_x, origin_y, width, height));
+        (planes, (origin_x, origin_y))
+    }
+
+    /// Returns the best scoring candidate for `needle` in `haystack`, as
+    /// returned by `correlation_planes`, or `None` if it does not fit.
+    fn best_candidate(
+        &self,
+        haystack: &Planes,
+        origin: (u32, u32),
+        needle: &Bitmap,
+    ) -> Option<Match> {
+        let mut best: Option<Match> = None;
+        self.scored_candidates(haystack, origin, needle, &mut |candidate| {
+            if best.is_none_or(|best| match_order(&candidate, &best) == Ordering::Less) {
+                best = Some(candidate);
+            }
+        });
+        best
+    }
+
+    /// Calls `scored` with the correlation score of `needle` at every
+    /// candidate point in `haystack`, as returned by `correlation_planes`, in
+    /// no particular order.
+    fn scored_candidates(
+        &self,
+        haystack: &Planes,
+        (origin_x, origin_y): (u32, u32),
+        needle: &Bitmap,
+        scored: &mut dyn FnMut(Match),
+    ) {
+        let (needle_width, needle_height) = (needle.image.width(), needle.image.height());
+        if self.is_needle_oversized(needle)
+            || needle_width == 0
+            || needle_height == 0
+            || needle_width as usize > haystack.width
+            || needle_height as usize > haystack.height
+        {
+            return;
+        }
+
+        let needle_planes = Planes::from_pixels(&needle.pixels());
+        let size =
+            Size::new(f64::from(needle_width), f64::from(needle_height)).scaled(self.multiplier());
+        ncc_scores(haystack, &needle_planes, |x, y, score| {
+            let point = Point::from_pixel(
+                f64::from(origin_x) + x as f64,
+                f64::from(origin_y) + y as f64,
+                self.scale,
+            );
+            scored(Match {
+                rect: Rect::new(point, size),
+                score,
+                scale: needle.scale,
+            });
+        });
+    }
+
+    #[inline]
+    fn multiplier(&self) -> f64 {
         1.0 / self.scale
     }
 
@@ -288,7 +1908,141 @@ impl Bitmap {
             || needle.bounds().size.height > self.bounds().size.height
     }
 
-    fn is_needle_at(&self, pt: Point, needle: &Bitmap, tolerance: Option<f64>) -> bool {
+    /// Returns the match for `needle` at `point`, as returned by `find`.
+    fn template_match(&self, point: Point, needle: &Bitmap, search: &TemplateSearch) -> Match {
+        let grid_point = point.scaled(self.multiplier()).round();
+        Match {
+            rect: Rect::new(point, needle.size),
+            score: search.score(grid_point.x as usize, grid_point.y as usize),
+            scale: needle.scale,
+        }
+    }
+
+    /// Returns `self` and `needle` sampled at each whole point, i.e. the
+    /// pixels compared by `is_needle_at`, for use with `TemplateSearch`.
+    fn search_grids<'a>(&'a self, needle: &'a Bitmap) -> (Grid<'a>, Grid<'a>) {
+        (
+            self.sampled_grid(self.size.width.ceil(), self.size.height.ceil()),
+            needle.sampled_grid(needle.size.width.trunc(), needle.size.height.trunc()),
+        )
+    }
+
+    /// Attempts to find `needle` as with `find_bitmap`, weighting each of its
+    /// pixels by `weights` if given, as described in `find_masked_bitmap`.
+    fn find_weighted_bitmap(
+        &self,
+        needle: &Bitmap,
+        weights: Option<&[f64]>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Point> {
+        if self.is_needle_oversized(needle) {
+            return None;
+        }
+
+        let (haystack_grid, needle_grid) = self.search_grids(needle);
+        let search = TemplateSearch::new(
+            &haystack_grid,
+            &needle_grid,
+            tolerance.unwrap_or(0.0),
+            weights,
+        );
+        self.find(&search, rect, start_point)
+    }
+
+    /// Calls `matched` with each point where `needle` is found, as with
+    /// `find_every_bitmap`, weighting its pixels as in `find_weighted_bitmap`.
+    fn find_every_weighted_bitmap(
+        &self,
+        needle: &Bitmap,
+        weights: Option<&[f64]>,
+        tolerance: Option<f64>,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+        matched: &mut dyn FnMut(Point),
+    ) {
+        if self.is_needle_oversized(needle) {
+            return;
+        }
+
+        let (haystack_grid, needle_grid) = self.search_grids(needle);
+        let search = TemplateSearch::new(
+            &haystack_grid,
+            &needle_grid,
+            tolerance.unwrap_or(0.0),
+            weights,
+        );
+        self.find_all(&search, rect, start_point, matched);
+    }
+
+    /// Returns the weight of each pixel in the grid sampled by
+    /// `search_grids`, taken from `mask` if given or the bitmap's own alpha
+    /// channel otherwise.
+    fn mask_weights(&self, mask: Option<&Bitmap>) -> Vec<f64> {
+        let grid = match mask {
+            Some(mask) => {
+                assert!(
+                    mask.image.dimensions() == self.image.dimensions() && mask.scale == self.scale,
+                    "Mask must be the same size as the needle."
+                );
+                mask.sampled_grid(self.size.width.trunc(), self.size.height.trunc())
+            }
+            None => self.sampled_grid(self.size.width.trunc(), self.size.height.trunc()),
+        };
+        grid.data
+            .chunks_exact(4)
+            .map(|pixel| {
+                let alpha = f64::from(pixel[3]) / 255.0;
+                if mask.is_some() {
+                    let luma = f64::from(Rgba::from_slice(pixel).to_luma()[0]) / 255.0;
+                    luma * alpha
+                } else {
+                    alpha
+                }
+            })
+            .collect()
+    }
+
+    fn sampled_grid(&self, width: f64, height: f64) -> Grid<'_> {
+        let pixels = self.pixels();
+        let (width, height) = (width.max(0.0) as usize, height.max(0.0) as usize);
+        if self.scale == 1.0
+            && (width, height) == (pixels.width() as usize, pixels.height() as usize)
+            && pixels.stride() == width * 4
+        {
+            return Grid {
+                width,
+                height,
+                data: pixels.data,
+            };
+        }
+
+        let max_x = pixels.width().saturating_sub(1);
+        let max_y = pixels.height().saturating_sub(1);
+        let sample = |i: usize, max: u32| ((i as f64 * self.multiplier()).round() as u32).min(max);
+        let columns: Vec<usize> = (0..width).map(|x| sample(x, max_x) as usize * 4).collect();
+        let mut data = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            let row = pixels.row(sample(y, max_y));
+            for &offset in &columns {
+                data.extend_from_slice(&row[offset..offset + 4]);
+            }
+        }
+        Grid {
+            width,
+            height,
+            data: Cow::Owned(data),
+        }
+    }
+
+    fn is_needle_at(
+        &self,
+        pt: Point,
+        needle: &Bitmap,
+        tolerance: Option<f64>,
+        metric: ColorMetric,
+    ) -> bool {
         let bounds = needle.bounds();
         for x in bounds.origin.x as u64..bounds.max_x() as u64 {
             for y in bounds.origin.y as u64..bounds.max_y() as u64 {
@@ -300,7 +2054,7 @@ impl Bitmap {
 
                 let c1 = needle.get_pixel(needle_point);
                 let c2 = self.get_pixel(haystack_point);
-                if !colors_match(c1, c2, tolerance.unwrap_or(0.0f64)) {
+                if !metric.matches(c1, c2, tolerance.unwrap_or(0.0f64)) {
                     return false;
                 }
             }
@@ -309,12 +2063,172 @@ impl Bitmap {
         true
     }
 
-    fn find<F: Fn(Point) -> bool>(
+    /// Returns the first point where `search` finds its needle, visiting
+    /// candidates in column-major order.
+    fn find(
+        &self,
+        search: &TemplateSearch,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+    ) -> Option<Point> {
+        let area = self.search_area(rect, start_point);
+        let candidates = search.candidates(area.usize_columns(), area.usize_rows());
+        let first_in_band = |band: Range<u64>| {
+            band.flat_map(|x| {
+                let rows = area.column_rows(x);
+                candidates
+                    .in_column(x as usize, rows.start as usize..rows.end as usize)
+                    .map(move |y| (x as usize, y))
+            })
+            .find(|&(x, y)| search.is_match(x, y))
+            .map(|(x, y)| Point::new(x as f64, y as f64))
+        };
+
+        #[cfg(feature = "parallel")]
+        let found = {
+            use rayon::prelude::*;
+            area.bands().into_par_iter().find_map_first(first_in_band)
+        };
+        #[cfg(not(feature = "parallel"))]
+        let found = first_in_band(area.columns());
+
+        found.map(|point| point.scaled(self.scale).round())
+    }
+
+    /// Calls `matched` with every point where `search` finds its needle, in
+    /// the same order as `find`.
+    fn find_all(
+        &self,
+        search: &TemplateSearch,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+        matched: &mut dyn FnMut(Point),
+    ) {
+        let area = self.search_area(rect, start_point);
+        let candidates = search.candidates(area.usize_columns(), area.usize_rows());
+        let in_band = |band: Range<u64>| {
+            band.flat_map(|x| {
+                let rows = area.column_rows(x);
+                candidates
+                    .in_column(x as usize, rows.start as usize..rows.end as usize)
+                    .map(move |y| (x as usize, y))
+            })
+            .filter(|&(x, y)| search.is_match(x, y))
+            .map(|(x, y)| Point::new(x as f64, y as f64))
+        };
+
+        #[cfg(feature = "parallel")]
+        let found = {
+            use rayon::prelude::*;
+            let bands: Vec<Vec<Point>> = area
+                .bands()
+                .into_par_iter()
+                .map(|band| in_band(band).collect())
+                .collect();
+            bands.into_iter().flatten()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let found = in_band(area.columns());
+
+        for point in found {
+            matched(point.scaled(self.scale).round());
+        }
+    }
+
+    /// Returns the first candidate point whose pixel satisfies `predicate`,
+    /// in the same order as `find`.
+    ///
+    /// Columns are scanned in strips a row at a time, which reads the
+    /// underlying buffer contiguously rather than jumping between rows.
+    fn find_pixel<F: Fn(Rgba<u8>) -> bool + Sync>(
         &self,
         rect: Option<Rect>,
         start_point: Option<Point>,
         predicate: F,
     ) -> Option<Point> {
+        let area = self.search_area(rect, start_point);
+        let sampler = PixelSampler::new(self);
+        let first_in_strip = |strip: Range<u64>| {
+            let offsets = sampler.offsets(strip.clone());
+            // Only columns before the best match so far can improve on it.
+            let mut end = strip.end;
+            let mut found = None;
+            for y in area.rows() {
+                if end == strip.start {
+                    break;
+                }
+                let row = sampler.row(y);
+                if let Some(x) = (strip.start..end).find(|&x| {
+                    area.contains(x, y)
+                        && predicate(PixelSampler::get(row, offsets[(x - strip.start) as usize]))
+                }) {
+                    end = x;
+                    found = Some(Point::new(x as f64, y as f64));
+                }
+            }
+            found
+        };
+        let first_in_band = |band: Range<u64>| area.strips(band).find_map(first_in_strip);
+
+        #[cfg(feature = "parallel")]
+        let found = {
+            use rayon::prelude::*;
+            area.bands().into_par_iter().find_map_first(first_in_band)
+        };
+        #[cfg(not(feature = "parallel"))]
+        let found = first_in_band(area.columns());
+
+        found.map(|point| point.scaled(self.scale).round())
+    }
+
+    /// Calls `matched` with each candidate point whose pixel satisfies
+    /// `predicate`, in the same order as `find_all`.
+    fn find_all_pixels<'a>(
+        &self,
+        rect: Option<Rect>,
+        start_point: Option<Point>,
+        predicate: &'a (dyn Fn(Rgba<u8>) -> bool + Sync),
+        matched: &'a mut dyn FnMut(Point),
+    ) {
+        let area = self.search_area(rect, start_point);
+        let sampler = PixelSampler::new(self);
+        let all_in_band = |band: Range<u64>| {
+            let mut points = Vec::new();
+            for strip in area.strips(band) {
+                let offsets = sampler.offsets(strip.clone());
+                let mut columns = vec![Vec::new(); offsets.len()];
+                for y in area.rows() {
+                    let row = sampler.row(y);
+                    for ((x, &offset), column) in strip.clone().zip(&offsets).zip(&mut columns) {
+                        if area.contains(x, y) && predicate(PixelSampler::get(row, offset)) {
+                            column.push(y);
+                        }
+                    }
+                }
+                for (x, rows) in (strip.start..).zip(columns) {
+                    points.extend(rows.into_iter().map(|y| Point::new(x as f64, y as f64)));
+                }
+            }
+            points
+        };
+
+        #[cfg(feature = "parallel")]
+        let found = {
+            use rayon::prelude::*;
+            let bands: Vec<Vec<Point>> = area.bands().into_par_iter().map(all_in_band).collect();
+            bands.into_iter().flatten()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let found = all_in_band(area.columns());
+
+        for point in found {
+            matched(point.scaled(self.scale).round());
+        }
+    }
+
+    /// Validates `rect` and `start_point` and returns the candidate points
+    /// they cover.
+    fn search_area(&self, rect: Option<Rect>, start_point: Option<Point>) -> SearchArea {
         let rect = rect.unwrap_or_else(|| self.bounds());
         let start_point = start_point.unwrap_or(self.bounds().origin);
         if !self.bounds().is_rect_visible(rect) {
@@ -334,81 +2248,334 @@ impl Bitmap {
 
         // TODO: Switch the Boyer-Moore algorithm for image search or use this instead
         // http://bit.ly/1EIEIfr.
-        let start_point = start_point.scaled(self.multiplier()).round();
-        let rect = rect.scaled(self.multiplier()).round();
-        let mut start_y = start_point.y;
-        for x in start_point.x as u64..rect.max_x() as u64 {
-            for y in start_y as u64..rect.max_y() as u64 {
-                let point = Point::new(x as f64, y as f64);
-                if predicate(point) {
-                    return Some(point.scaled(self.scale).round());
+        SearchArea {
+            start_point: start_point.scaled(self.multiplier()).round(),
+            rect: rect.scaled(self.multiplier()).round(),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn system_copy_to_pasteboard(&self) -> ImageResult<()> {
+        use objc2::AnyThread;
+        use objc2::rc::Retained;
+        use objc2::runtime::ProtocolObject;
+        use objc2_app_kit::{NSImage, NSPasteboard, NSPasteboardWriting};
+        use objc2_foundation::{NSData, NSArray};
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut cursor = std::io::Cursor::new(&mut buffer);
+        self.image.write_to(&mut cursor, ImageFormat::Png)?;
+        unsafe {
+            let data = NSData::dataWithBytes_length(
+                buffer.as_ptr() as *const std::ffi::c_void,
+                buffer.len(),
+            );
+            let image: Retained<NSImage> = NSImage::initWithData(NSImage::alloc(), &data).ok_or(
+                std::io::Error::other("Failed to create NSImage from NSData")
+            )?;
+            let writing: &ProtocolObject<dyn NSPasteboardWriting> = ProtocolObject::from_ref(&*image);
+            let objects = &NSArray::arrayWithObject(writing);
+            let pasteboard = NSPasteboard::generalPasteboard();
+            pasteboard.clearContents();
+            pasteboard.writeObjects(objects);
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn system_copy_to_pasteboard(&self) -> ImageResult<()> {
+        panic!("Unsupported OS");
+    }
+
+    #[cfg(target_os = "linux")]
+    fn system_copy_to_pasteboard(&self) -> ImageResult<()> {
+        panic!("Unsupported OS");
+    }
+}
+
+/// A group of pixels connected horizontally, vertically or diagonally.
+struct PixelComponent {
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
+    count: usize,
+    sum_x: u64,
+    sum_y: u64,
+}
+
+impl PixelComponent {
+    /// Returns the bounds of the component in points.
+    fn bounds(&self, scale: f64) -> Rect {
+        Rect::new(
+            Point::from_pixel(f64::from(self.min_x), f64::from(self.min_y), scale),
+            Size::new(
+                f64::from(self.max_x - self.min_x + 1),
+                f64::from(self.max_y - self.min_y + 1),
+            )
+            .scaled(1.0 / scale),
+        )
+    }
+
+    /// Returns the mean position of the pixel centers in the component, in
+    /// points.
+    fn centroid(&self, scale: f64) -> Point {
+        let count = self.count as f64;
+        Point::from_pixel(
+            self.sum_x as f64 / count + 0.5,
+            self.sum_y as f64 / count + 0.5,
+            scale,
+        )
+    }
+}
+
+/// Returns the connected components of the set pixels in `mask`, a row-major
+/// image of the given size, ordered by their first pixel.
+fn pixel_components(width: u32, height: u32, mask: &[bool]) -> Vec<PixelComponent> {
+    let (width, height) = (width as usize, height as usize);
+    let mut visited = vec![false; mask.len()];
+    let mut components = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..mask.len() {
+        if !mask[start] || visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        stack.push(start);
+        let (x, y) = ((start % width) as u32, (start / width) as u32);
+        let mut component = PixelComponent {
+            min_x: x,
+            min_y: y,
+            max_x: x,
+            max_y: y,
+            count: 0,
+            sum_x: 0,
+            sum_y: 0,
+        };
+        while let Some(idx) = stack.pop() {
+            let (x, y) = (idx % width, idx / width);
+            component.min_x = component.min_x.min(x as u32);
+            component.min_y = component.min_y.min(y as u32);
+            component.max_x = component.max_x.max(x as u32);
+            component.max_y = component.max_y.max(y as u32);
+            component.count += 1;
+            component.sum_x += x as u64;
+            component.sum_y += y as u64;
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let neighbor = ny * width + nx;
+                    if mask[neighbor] && !visited[neighbor] {
+                        visited[neighbor] = true;
+                        stack.push(neighbor);
+                    }
                 }
             }
-            start_y = rect.origin.y;
         }
-
-        None
+        components.push(component);
     }
+    components
+}
 
-    fn find_all<'a>(
-        &self,
-        rect: Option<Rect>,
-        start_point: Option<Point>,
-        predicate: &'a dyn Fn(Point) -> bool,
-        matched: &'a mut dyn FnMut(Point),
-    ) {
-        let rect = rect.unwrap_or_else(|| self.bounds());
-        let mut start_point = start_point.unwrap_or(self.bounds().origin);
-        loop {
-            if let Some(point) = self.find(Some(rect), Some(start_point), predicate) {
-                matched(point);
-                if let Some(next_point) = rect.iter_point(point) {
-                    start_point = next_point;
-                    continue;
+/// Returns the connected components of `mask` as with `pixel_components`,
+/// merging components whose bounds overlap or share an edge or corner until
+/// none do. Only the bounds of the returned components are meaningful.
+///
+/// Each round fills the bounds of every component and labels the result
+/// again, so merging takes time proportional to the size of the mask rather
+/// than the square of the number of components.
+fn merged_components(width: u32, height: u32, mask: &[bool]) -> Vec<PixelComponent> {
+    let (w, h) = (width as usize, height as usize);
+    let mut components = pixel_components(width, height, mask);
+    loop {
+        // Mark the corners of each bounds in a 2D difference array, whose
+        // prefix sums count the bounds covering each pixel.
+        let mut coverage = vec![0i64; (w + 1) * (h + 1)];
+        for component in &components {
+            let (min_x, min_y) = (component.min_x as usize, component.min_y as usize);
+            let (max_x, max_y) = (component.max_x as usize + 1, component.max_y as usize + 1);
+            coverage[min_y * (w + 1) + min_x] += 1;
+            coverage[min_y * (w + 1) + max_x] -= 1;
+            coverage[max_y * (w + 1) + min_x] -= 1;
+            coverage[max_y * (w + 1) + max_x] += 1;
+        }
+        for y in 0..=h {
+            for x in 0..=w {
+                let idx = y * (w + 1) + x;
+                if x > 0 {
+                    coverage[idx] += coverage[idx - 1];
+                }
+                if y > 0 {
+                    coverage[idx] += coverage[idx - (w + 1)];
+                }
+                if x > 0 && y > 0 {
+                    coverage[idx] -= coverage[idx - (w + 1) - 1];
                 }
             }
+        }
+        let filled: Vec<bool> = (0..w * h)
+            .map(|idx| coverage[idx / w * (w + 1) + idx % w] > 0)
+            .collect();
+
+        // Bounds that touch are 8-connected once filled, so the number of
+        // components only stays the same once nothing is left to merge.
+        let merged = pixel_components(width, height, &filled);
+        if merged.len() == components.len() {
+            return merged;
+        }
+        components = merged;
+    }
+}
+
+/// Returns the top-left `count` by `count` coefficients of the 2D DCT-II of a
+/// square image of the given size, in row-major order.
+fn low_frequencies(values: &[f64], size: usize, count: usize) -> Vec<f64> {
+    let basis: Vec<f64> = (0..count)
+        .flat_map(|frequency| {
+            (0..size).map(move |x| {
+                (std::f64::consts::PI * (2 * x + 1) as f64 * frequency as f64
+                    / (2 * size) as f64)
+                    .cos()
+            })
+        })
+        .collect();
+
+    // Transform rows, then columns.
+    let mut rows = vec![0.0; size * count];
+    for y in 0..size {
+        for u in 0..count {
+            rows[y * count + u] = (0..size)
+                .map(|x| values[y * size + x] * basis[u * size + x])
+                .sum();
+        }
+    }
+    let mut coefficients = vec![0.0; count * count];
+    for v in 0..count {
+        for u in 0..count {
+            coefficients[v * count + u] = (0..size)
+                .map(|y| rows[y * count + u] * basis[v * size + y])
+                .sum();
+        }
+    }
+    coefficients
+}
+
+/// Score at which `find_bitmap_at_scales` stops trying further factors.
+const CONFIDENT_SCORE: f64 = 0.99;
+
+/// Number of columns scanned together by `find_pixel` and
+/// `find_all_pixels`.
+const STRIP_WIDTH: u64 = 64;
+
+/// Reads the pixels sampled by `Bitmap::get_pixel` for candidate points.
+struct PixelSampler<'a> {
+    pixels: PixelView<'a>,
+    multiplier: f64,
+}
+
+impl<'a> PixelSampler<'a> {
+    fn new(bitmap: &'a Bitmap) -> PixelSampler<'a> {
+        PixelSampler {
+            pixels: bitmap.pixels(),
+            multiplier: bitmap.multiplier(),
+        }
+    }
+
+    #[inline]
+    fn row(&self, y: u64) -> &[u8] {
+        self.pixels.row((y as f64 * self.multiplier).round() as u32)
+    }
+
+    /// Returns the byte offset within a row of the pixel sampled for each
+    /// of the given columns.
+    fn offsets(&self, columns: Range<u64>) -> Vec<usize> {
+        columns
+            .map(|x| (x as f64 * self.multiplier).round() as usize * 4)
+            .collect()
+    }
+
+    #[inline]
+    fn get(row: &[u8], offset: usize) -> Rgba<u8> {
+        let pixel = &row[offset..offset + 4];
+        Rgba([pixel[0], pixel[1], pixel[2], pixel[3]])
+    }
+}
+
+/// Candidate points of a search, visited in column-major order from
+/// `start_point` to the end of `rect`.
+#[derive(Copy, Clone)]
+struct SearchArea {
+    start_point: Point,
+    rect: Rect,
+}
 
-            break;
-        }
+impl SearchArea {
+    #[inline]
+    fn columns(&self) -> Range<u64> {
+        self.start_point.x as u64..self.rect.max_x() as u64
     }
 
-    #[cfg(target_os = "macos")]
-    fn system_copy_to_pasteboard(&self) -> ImageResult<()> {
-        use objc2::AnyThread;
-        use objc2::rc::Retained;
-        use objc2::runtime::ProtocolObject;
-        use objc2_app_kit::{NSImage, NSPasteboard, NSPasteboardWriting};
-        use objc2_foundation::{NSData, NSArray};
-        use image::ImageFormat;
+    /// Returns every row containing a candidate point in any column.
+    #[inline]
+    fn rows(&self) -> Range<u64> {
+        self.start_point.y.min(self.rect.origin.y) as u64..self.rect.max_y() as u64
+    }
 
-        let mut buffer: Vec<u8> = Vec::new();
-        let mut cursor = std::io::Cursor::new(&mut buffer);
-        self.image.write_to(&mut cursor, ImageFormat::Png)?;
-        unsafe {
-            let data = NSData::dataWithBytes_length(
-                buffer.as_ptr() as *const std::ffi::c_void,
-                buffer.len(),
-            );
-            let image: Retained<NSImage> = NSImage::initWithData(NSImage::alloc(), &data).ok_or(
-                std::io::Error::other("Failed to create NSImage from NSData")
-            )?;
-            let writing: &ProtocolObject<dyn NSPasteboardWriting> = ProtocolObject::from_ref(&*image);
-            let objects = &NSArray::arrayWithObject(writing);
-            let pasteboard = NSPasteboard::generalPasteboard();
-            pasteboard.clearContents();
-            pasteboard.writeObjects(objects);
+    /// Returns true if `(x, y)` is a candidate point, given that `x` is one
+    /// of `columns` and `y` one of `rows`.
+    #[inline]
+    fn contains(&self, x: u64, y: u64) -> bool {
+        if x == self.start_point.x as u64 {
+            y >= self.start_point.y as u64
+        } else {
+            y >= self.rect.origin.y as u64
         }
-        Ok(())
     }
 
-    #[cfg(windows)]
-    fn system_copy_to_pasteboard(&self) -> ImageResult<()> {
-        panic!("Unsupported OS");
+    /// Splits the given range of columns into strips of `STRIP_WIDTH`.
+    fn strips(&self, columns: Range<u64>) -> impl Iterator<Item = Range<u64>> {
+        (columns.start..columns.end)
+            .step_by(STRIP_WIDTH as usize)
+            .map(move |start| start..(start + STRIP_WIDTH).min(columns.end))
     }
 
-    #[cfg(target_os = "linux")]
-    fn system_copy_to_pasteboard(&self) -> ImageResult<()> {
-        panic!("Unsupported OS");
+    /// Returns the rows of the candidate points in column `x`.
+    #[inline]
+    fn column_rows(&self, x: u64) -> Range<u64> {
+        let start_y = if x == self.start_point.x as u64 {
+            self.start_point.y
+        } else {
+            self.rect.origin.y
+        };
+        start_y as u64..self.rect.max_y() as u64
+    }
+
+    /// Returns `columns` as indices into a `Grid`.
+    #[inline]
+    fn usize_columns(&self) -> Range<usize> {
+        let columns = self.columns();
+        columns.start as usize..columns.end as usize
+    }
+
+    /// Returns `rows` as indices into a `Grid`.
+    #[inline]
+    fn usize_rows(&self) -> Range<usize> {
+        let rows = self.rows();
+        rows.start as usize..rows.end as usize
+    }
+
+    /// Splits the columns into contiguous bands to be searched in parallel.
+    #[cfg(feature = "parallel")]
+    fn bands(&self) -> Vec<Range<u64>> {
+        let columns = self.columns();
+        let count = columns.end.saturating_sub(columns.start);
+        let band_count = (rayon::current_num_threads() as u64 * 4).clamp(1, count.max(1));
+        let band_width = count.div_ceil(band_count).max(1);
+        (0..band_count)
+            .map(|band| {
+                let start = (columns.start + band * band_width).min(columns.end);
+                start..(start + band_width).min(columns.end)
+            })
+            .collect()
     }
 }
 
@@ -417,7 +2584,7 @@ impl Bitmap {
 /// Tolerance is defined as a double in the range from 0 to 1, where 0 is an
 /// exact match and 1 matches anything.
 #[inline]
-fn colors_match(c1: Rgba<u8>, c2: Rgba<u8>, tolerance: f64) -> bool {
+pub(crate) fn colors_match(c1: Rgba<u8>, c2: Rgba<u8>, tolerance: f64) -> bool {
     assert!(
         (0.0..=1.0).contains(&tolerance),
         "Tolerance must be between 0 and 1."
@@ -434,14 +2601,128 @@ fn colors_match(c1: Rgba<u8>, c2: Rgba<u8>, tolerance: f64) -> bool {
     (d1 * d1 + d2 * d2 + d3 * d3).sqrt() <= tolerance * MAX_TOLERANCE_DELTA
 }
 
-const MAX_TOLERANCE_DELTA: f64 = 441.672_955_930_1; // => (3.0f64 * 255.0f64 * 255.0f64).sqrt();
+/// Returns true if the given two colors are sufficiently similar, with the
+/// tolerance loosened by one minus `weight`, a float in the range from 0
+/// to 1. A needle pixel with weight `w` that is blended over any background
+/// differs from it by at most `1 - w`, so colors with a weight of 0 always
+/// match.
+#[inline]
+pub(crate) fn weighted_colors_match(
+    c1: Rgba<u8>,
+    c2: Rgba<u8>,
+    weight: f64,
+    tolerance: f64,
+) -> bool {
+    assert!(
+        (0.0..=1.0).contains(&tolerance),
+        "Tolerance must be between 0 and 1."
+    );
+    let tolerance = tolerance + (1.0 - weight.clamp(0.0, 1.0));
+    if tolerance >= 1.0 {
+        return true;
+    }
+    if tolerance == 0.0 {
+        return c1.to_rgb() == c2.to_rgb();
+    }
+
+    let c1_channels = c1.channels();
+    let c2_channels = c2.channels();
+    let d1: f64 = (f64::from(c1_channels[0]) - f64::from(c2_channels[0])).abs();
+    let d2: f64 = (f64::from(c1_channels[1]) - f64::from(c2_channels[1])).abs();
+    let d3: f64 = (f64::from(c1_channels[2]) - f64::from(c2_channels[2])).abs();
+    (d1 * d1 + d2 * d2 + d3 * d3).sqrt() <= tolerance * MAX_TOLERANCE_DELTA
+}
+
+/// Color used by `Bitmap::annotated` to outline the search rect.
+const SEARCH_RECT_COLOR: Rgba<u8> = Rgba([0, 120, 255, 255]);
+
+/// Color used by `Bitmap::annotated` to mark the start point.
+const START_POINT_COLOR: Rgba<u8> = Rgba([255, 140, 0, 255]);
+
+/// Color used by `Bitmap::annotated` to outline matches.
+const MATCH_COLOR: Rgba<u8> = Rgba([0, 190, 0, 255]);
+
+/// Orders matches from best to worst score, breaking ties in the
+/// column-major order used by `find_bitmap`.
+fn match_order(a: &Match, b: &Match) -> Ordering {
+    b.score
+        .total_cmp(&a.score)
+        .then(a.rect.origin.x.total_cmp(&b.rect.origin.x))
+        .then(a.rect.origin.y.total_cmp(&b.rect.origin.y))
+}
+
+/// Composites `color` over `pixel`. Unlike `Pixel::blend`, this keeps opaque
+/// pixels fully opaque.
+fn blend(pixel: &mut Rgba<u8>, color: Rgba<u8>) {
+    let alpha = f64::from(color[3]) / 255.0;
+    let under = f64::from(pixel[3]) / 255.0 * (1.0 - alpha);
+    let out = alpha + under;
+    if out == 0.0 {
+        *pixel = Rgba([0, 0, 0, 0]);
+        return;
+    }
+    for channel in 0..3 {
+        let value = (f64::from(color[channel]) * alpha + f64::from(pixel[channel]) * under) / out;
+        pixel[channel] = value.round() as u8;
+    }
+    pixel[3] = (out * 255.0).round() as u8;
+}
+
+/// Returns the rect between the given edges, which may be empty if they are
+/// reversed.
+#[inline]
+fn pixel_rect(min_x: f64, min_y: f64, max_x: f64, max_y: f64) -> Rect {
+    Rect::new(Point::new(min_x, min_y), Size::new(max_x - min_x, max_y - min_y))
+}
+
+/// Keyword of the PNG text chunk holding the scale of a saved bitmap.
+const SCALE_KEYWORD: &str = "autopilot:scale";
+
+/// Resolution of a bitmap with a scale of 1, i.e. 72 DPI.
+const PIXELS_PER_METER: f64 = 72.0 / 0.0254;
+
+/// Returns the scale stored in the metadata of a PNG, if any.
+fn png_scale(bytes: &[u8]) -> ImageResult<Option<f64>> {
+    let reader = png::Decoder::new(std::io::Cursor::new(bytes))
+        .read_info()
+        .map_err(|err| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Exact(ImageFormat::Png),
+                err,
+            ))
+        })?;
+    let info = reader.info();
+    let text_scale = info
+        .uncompressed_latin1_text
+        .iter()
+        .find(|chunk| chunk.keyword == SCALE_KEYWORD)
+        .and_then(|chunk| chunk.text.trim().parse::<f64>().ok())
+        .filter(|scale| scale.is_finite() && *scale > 0.0);
+    let resolution_scale = info
+        .pixel_dims
+        .filter(|dims| dims.unit == png::Unit::Meter && dims.xppu == dims.yppu)
+        .map(|dims| f64::from(dims.xppu) / PIXELS_PER_METER)
+        .filter(|scale| scale.round() >= 1.0 && (scale - scale.round()).abs() < 0.01)
+        .map(f64::round);
+    Ok(text_scale.or(resolution_scale))
+}
+
+pub(crate) fn png_encoding_error(err: png::EncodingError) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Png),
+        err,
+    ))
+}
+
+pub(crate) const MAX_TOLERANCE_DELTA: f64 = 441.672_955_930_1; // => (3.0f64 * 255.0f64 * 255.0f64).sqrt();
 
 /// Returns a screengrab of the entire main display.
 pub fn capture_screen() -> ImageResult<Bitmap> {
     capture_screen_portion(Rect::new(Point::ZERO, screen::size()))
 }
 
-/// Returns a screengrab of the given portion of the main display.
+/// Returns a screengrab of the given portion of the screen, which may lie on
+/// any display or span several of them.
 pub fn capture_screen_portion(rect: Rect) -> ImageResult<Bitmap> {
     if !screen::is_rect_visible(rect) {
         Err(ImageError::Limits(LimitError::from_kind(
@@ -452,6 +2733,164 @@ pub fn capture_screen_portion(rect: Rect) -> ImageResult<Bitmap> {
     }
 }
 
+/// Returns a screengrab of the contents of the X11 window with the given id,
+/// together with the origin of the window on screen in points.
+///
+/// Covered parts of the window are only captured correctly while a
+/// compositing manager is running, in which case the window is read from the
+/// offscreen pixmap it is rendered to. Otherwise only the parts of the window
+/// that are visible on screen are captured correctly. Returns an error if
+/// the window does not exist or is not mapped.
+#[cfg(target_os = "linux")]
+pub fn capture_window(window_id: u64) -> ImageResult<(Bitmap, Point)> {
+    let capture_error = || {
+        ImageError::IoError(std::io::Error::other(
+            "Could not capture window".to_string(),
+        ))
+    };
+    internal::X_MAIN_DISPLAY.with(|display| {
+        let display = display.as_ptr();
+        let window = window_id as x11::xlib::Window;
+        let mut attributes: x11::xlib::XWindowAttributes = unsafe { std::mem::zeroed() };
+        let (mut x, mut y) = (0, 0);
+        let found = internal::trap_x_errors(display, || unsafe {
+            let mut child: x11::xlib::Window = 0;
+            x11::xlib::XGetWindowAttributes(display, window, &mut attributes);
+            x11::xlib::XTranslateCoordinates(
+                display,
+                window,
+                x11::xlib::XDefaultRootWindow(display),
+                0,
+                0,
+                &mut x,
+                &mut y,
+                &mut child,
+            );
+        });
+        if !found
+            || attributes.map_state != x11::xlib::IsViewable
+            || attributes.width <= 0
+            || attributes.height <= 0
+        {
+            return Err(capture_error());
+        }
+
+        let (width, height) = (attributes.width as u32, attributes.height as u32);
+        let mut image: *mut x11::xlib::XImage = std::ptr::null_mut();
+
+        // Naming the pixmap only succeeds if the window is already redirected
+        // offscreen, e.g. by a compositing manager. Redirecting it here
+        // instead would leave covered areas undefined until the client
+        // repaints them. The pixmap includes the border of the window.
+        let from_pixmap = has_name_window_pixmap(display)
+            && internal::trap_x_errors(display, || unsafe {
+                let pixmap = XCompositeNameWindowPixmap(display, window);
+                image = x11::xlib::XGetImage(
+                    display,
+                    pixmap,
+                    attributes.border_width,
+                    attributes.border_width,
+                    width,
+                    height,
+                    x11::xlib::XAllPlanes(),
+                    x11::xlib::ZPixmap,
+                );
+                x11::xlib::XFreePixmap(display, pixmap);
+            })
+            && !image.is_null();
+        let captured = from_pixmap
+            || internal::trap_x_errors(display, || unsafe {
+                if !image.is_null() {
+                    x11::xlib::XDestroyImage(image);
+                }
+                image = x11::xlib::XGetImage(
+                    display,
+                    window,
+                    0,
+                    0,
+                    width,
+                    height,
+                    x11::xlib::XAllPlanes(),
+                    x11::xlib::ZPixmap,
+                );
+            });
+        let image = unsafe {
+            guard(image, |i| {
+                if !i.is_null() {
+                    x11::xlib::XDestroyImage(i);
+                }
+            })
+        };
+        if !captured || image.is_null() {
+            return Err(capture_error());
+        }
+
+        let scale = screen::scale();
+        let img = rgba_from_ximage(unsafe { &**image });
+        Ok((
+            Bitmap::new(DynamicImage::ImageRgba8(img), Some(scale)),
+            Point::from_pixel(f64::from(x), f64::from(y), scale),
+        ))
+    })
+}
+
+/// Returns true if the server supports `XCompositeNameWindowPixmap`, which
+/// was added in version 0.2 of the Composite extension.
+#[cfg(target_os = "linux")]
+fn has_name_window_pixmap(display: *mut x11::xlib::Display) -> bool {
+    let (mut event_base, mut error_base) = (0, 0);
+    let (mut major, mut minor) = (0, 0);
+    unsafe {
+        XCompositeQueryExtension(display, &mut event_base, &mut error_base) != 0
+            && XCompositeQueryVersion(display, &mut major, &mut minor) != 0
+            && (major, minor) >= (0, 2)
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe extern "C" {
+    unsafe fn XCompositeQueryExtension(
+        display: *mut x11::xlib::Display,
+        event_base: *mut libc::c_int,
+        error_base: *mut libc::c_int,
+    ) -> x11::xlib::Bool;
+    unsafe fn XCompositeQueryVersion(
+        display: *mut x11::xlib::Display,
+        major_version: *mut libc::c_int,
+        minor_version: *mut libc::c_int,
+    ) -> x11::xlib::Status;
+    unsafe fn XCompositeNameWindowPixmap(
+        display: *mut x11::xlib::Display,
+        window: x11::xlib::Window,
+    ) -> x11::xlib::Pixmap;
+}
+
+/// Copies a captured buffer with the given layout into an RGBA image.
+/// `channels` holds the byte offsets of the red, green and blue channels
+/// within each pixel.
+fn rgba_from_raw(
+    buffer: &[u8],
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+    bytes_per_pixel: usize,
+    channels: [usize; 3],
+) -> RgbaImage {
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for row in buffer.chunks(bytes_per_row).take(height) {
+        for pixel in row[..width * bytes_per_pixel].chunks_exact(bytes_per_pixel) {
+            pixels.extend_from_slice(&[
+                pixel[channels[0]],
+                pixel[channels[1]],
+                pixel[channels[2]],
+                255,
+            ]);
+        }
+    }
+    RgbaImage::from_raw(width as u32, height as u32, pixels)
+        .expect("captured buffer is smaller than its dimensions")
+}
+
 #[cfg(target_os = "macos")]
 fn system_capture_screen_portion(rect: Rect) -> ImageResult<Bitmap> {
     use core_graphics::display::CGDisplay;
@@ -555,22 +2994,28 @@ fn system_capture_screen_portion(rect: Rect) -> ImageResult<Bitmap> {
 
     let buflen: usize = rect.size.height as usize * bytewidth;
     let buffer: &[u8] = unsafe { std::slice::from_raw_parts(data as *mut u8, buflen) };
-    let mut img = DynamicImage::new_rgb8(rect.size.width as u32, rect.size.height as u32);
-    for x in 0..rect.size.width as usize {
-        for y in 0..rect.size.height as usize {
-            let offset: usize = bytewidth * y + bytes_per_pixel * x;
-            let (b, g, r) = (buffer[offset], buffer[offset + 1], buffer[offset + 2]);
-            img.put_pixel(x as u32, y as u32, Rgba([r, g, b, 255]));
-        }
-    }
+    let img = rgba_from_raw(
+        buffer,
+        rect.size.width as usize,
+        rect.size.height as usize,
+        bytewidth,
+        bytes_per_pixel,
+        [2, 1, 0],
+    );
 
-    Ok(Bitmap::new(img, Some(screen::scale())))
+    Ok(Bitmap::new(DynamicImage::ImageRgba8(img), Some(screen::scale())))
 }
 
 #[cfg(target_os = "linux")]
 fn system_capture_screen_portion(rect: Rect) -> ImageResult<Bitmap> {
+    let scaled_rect = rect.scaled(screen::scale());
+    let (x, y) = (scaled_rect.origin.x as i32, scaled_rect.origin.y as i32);
+    let (width, height) = (scaled_rect.size.width as u32, scaled_rect.size.height as u32);
+    if let Some(img) = shm::capture(x, y, width, height, rgba_from_ximage) {
+        return Ok(Bitmap::new(DynamicImage::ImageRgba8(img), Some(screen::scale())));
+    }
+
     internal::X_MAIN_DISPLAY.with(|display| {
-        let scaled_rect = rect.scaled(screen::scale());
         let root_window = unsafe {
             guard(x11::xlib::XDefaultRootWindow(display.as_ptr()), |w| {
                 x11::xlib::XDestroyWindow(display.as_ptr(), w);
@@ -581,10 +3026,10 @@ fn system_capture_screen_portion(rect: Rect) -> ImageResult<Bitmap> {
                 x11::xlib::XGetImage(
                     display.as_ptr(),
                     *root_window,
-                    scaled_rect.origin.x as i32,
-                    scaled_rect.origin.y as i32,
-                    scaled_rect.size.width as u32,
-                    scaled_rect.size.height as u32,
+                    x,
+                    y,
+                    width,
+                    height,
                     x11::xlib::XAllPlanes(),
                     x11::xlib::ZPixmap,
                 ),
@@ -598,24 +3043,29 @@ fn system_capture_screen_portion(rect: Rect) -> ImageResult<Bitmap> {
                 "Could not capture screen portion".to_string()
             )));
         }
-        let image = unsafe { **image_ptr };
-        let bytes_per_pixel = image.bits_per_pixel / 8;
-        let buflen: usize = image.width as usize * image.height as usize * bytes_per_pixel as usize;
-        let buffer: &[u8] = unsafe { std::slice::from_raw_parts(image.data as *mut u8, buflen) };
-        let mut img = DynamicImage::new_rgb8(image.width as u32, image.height as u32);
-        for x in 0..image.width {
-            for y in 0..image.height {
-                let offset: usize = image.bytes_per_line as usize * y as usize
-                    + bytes_per_pixel as usize * x as usize;
-                let (b, g, r) = (buffer[offset], buffer[offset + 1], buffer[offset + 2]);
-                img.put_pixel(x as u32, y as u32, Rgba([r, g, b, 255]));
-            }
-        }
-        let bmp = Bitmap::new(img, Some(screen::scale()));
+        let img = rgba_from_ximage(unsafe { &**image_ptr });
+        let bmp = Bitmap::new(DynamicImage::ImageRgba8(img), Some(screen::scale()));
         Ok(bmp)
     })
 }
 
+/// Copies the pixels of an `XImage` in the 32-bit BGRX format used by
+/// TrueColor displays into an RGBA image.
+#[cfg(target_os = "linux")]
+fn rgba_from_ximage(image: &x11::xlib::XImage) -> RgbaImage {
+    let bytes_per_pixel = image.bits_per_pixel as usize / 8;
+    let buflen: usize = image.bytes_per_line as usize * image.height as usize;
+    let buffer: &[u8] = unsafe { std::slice::from_raw_parts(image.data as *mut u8, buflen) };
+    rgba_from_raw(
+        buffer,
+        image.width as usize,
+        image.height as usize,
+        image.bytes_per_line as usize,
+        bytes_per_pixel,
+        [2, 1, 0],
+    )
+}
+
 #[cfg(target_os = "macos")]
 fn macos_load_cgimage(image: &CGImage) -> ImageResult<Bitmap> {
     use core_graphics::base::CGFloat;
@@ -648,23 +3098,22 @@ fn macos_load_cgimage(image: &CGImage) -> ImageResult<Bitmap> {
     context.draw_image(rect, image);
 
     let buffer: &[u8] = context.data();
-    let mut dynimage = DynamicImage::new_rgb8(width as u32, height as u32);
-    for x in 0..width {
-        for y in 0..height {
-            let offset = bytes_per_row * y + bytes_per_pixel * x;
-            let (r, g, b) = (buffer[offset], buffer[offset + 1], buffer[offset + 2]);
-            dynimage.put_pixel(x as u32, y as u32, Rgba([r, g, b, 255]));
-        }
-    }
-    let bmp = Bitmap::new(dynimage, Some(screen::scale()));
+    let img = rgba_from_raw(buffer, width, height, bytes_per_row, bytes_per_pixel, [0, 1, 2]);
+    let bmp = Bitmap::new(DynamicImage::ImageRgba8(img), Some(screen::scale()));
     Ok(bmp)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::bitmap::{capture_screen, capture_screen_portion, colors_match, Bitmap};
+    use crate::bitmap::{capture_screen, capture_screen_portion, colors_match, Bitmap, Overlap};
+    use crate::bitmap::{suppress_overlapping_matches, Match, MatchMode, ScaleRange};
+    use crate::bitmap::{ColorMetric, ColorRange, ColorRegion, HashAlgorithm, ImageHash};
+    use crate::bitmap::ResampleFilter;
+    use crate::bitmap::{blend, png_scale};
+    use crate::bitmap::{MATCH_COLOR, SEARCH_RECT_COLOR, START_POINT_COLOR};
     use crate::geometry::{Point, Rect, Size};
-    use image::{DynamicImage, Rgba, RgbaImage};
+    use image::imageops::FilterType;
+    use image::{DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
     use image::{GenericImage, GenericImageView};
     use quickcheck::{Arbitrary, Gen, TestResult};
     use rand::{rng, Rng};
@@ -701,6 +3150,669 @@ mod tests {
         assert_eq!(portion, cropped)
     }
 
+    #[test]
+    fn test_scale_range_factors() {
+        assert_eq!(
+            ScaleRange::new(1.0, 2.0, 0.25).unwrap().factors(),
+            vec![1.0, 1.25, 1.5, 1.75, 2.0]
+        );
+        assert_eq!(ScaleRange::exact(1.5).unwrap().factors(), vec![1.5]);
+        assert_eq!(ScaleRange::default().factors().len(), 12);
+        assert!(ScaleRange::new(2.0, 1.0, 0.25).is_none());
+        assert!(ScaleRange::new(0.0, 1.0, 0.25).is_none());
+        assert!(ScaleRange::new(1.0, 2.0, 0.0).is_none());
+        assert!(ScaleRange::new(1.0, f64::INFINITY, 0.25).is_none());
+        assert!(ScaleRange::exact(f64::NAN).is_none());
+    }
+
+    #[test]
+    fn test_find_bitmap_at_scales() {
+        let needle_img = RgbaImage::from_fn(12, 10, |x, y| {
+            let v = (x * 37 + y * 91 + x * y * 13) as u8;
+            Rgba([v, v.wrapping_mul(7), 255 - v, 255])
+        });
+        let needle = Bitmap::new(DynamicImage::ImageRgba8(needle_img.clone()), None);
+        let upscaled =
+            DynamicImage::ImageRgba8(needle_img).resize_exact(24, 20, FilterType::Triangle);
+        let mut haystack_img = DynamicImage::new_rgba8(80, 60);
+        for x in 0..24 {
+            for y in 0..20 {
+                haystack_img.put_pixel(x + 30, y + 16, upscaled.get_pixel(x, y));
+            }
+        }
+        let haystack = Bitmap::new(haystack_img, Some(2.0));
+        let (found, factor) = haystack
+            .find_bitmap_at_scales(
+                &needle,
+                ScaleRange::new(1.0, 3.0, 0.5).unwrap(),
+                0.9,
+                None,
+            )
+            .unwrap();
+        assert_eq!(found.rect, Rect::new(Point::new(15.0, 8.0), Size::new(12.0, 10.0)));
+        assert_eq!(factor, 2.0);
+        assert_eq!(found.scale, 2.0);
+        assert!(found.score > 0.999);
+    }
+
+    #[test]
+    fn test_find_masked_bitmap() {
+        // A diamond-shaped icon with transparent corners, drawn onto a
+        // gradient background.
+        // Its outline is anti-aliased, so only partly covers the background.
+        let icon = RgbaImage::from_fn(9, 9, |x, y| {
+            let distance = (x as i32 - 4).abs() + (y as i32 - 4).abs();
+            let alpha = match distance {
+                0..=3 => 255,
+                4 => 128,
+                _ => 0,
+            };
+            Rgba([200, (x * 20) as u8, (y * 20) as u8, alpha])
+        });
+        let haystack_img = RgbaImage::from_fn(40, 30, |x, y| {
+            let mut pixel = Rgba([(x * 6) as u8, (y * 8) as u8, 90, 255]);
+            let (ix, iy) = (x.wrapping_sub(21), y.wrapping_sub(13));
+            if ix < 9 && iy < 9 {
+                blend(&mut pixel, *icon.get_pixel(ix, iy));
+            }
+            pixel
+        });
+        let haystack = Bitmap::new(DynamicImage::ImageRgba8(haystack_img), None);
+        let needle = Bitmap::new(DynamicImage::ImageRgba8(icon.clone()), None);
+        let expected = Some(Point::new(21.0, 13.0));
+        assert_eq!(haystack.find_bitmap(&needle, Some(0.1), None, None), None);
+        assert_eq!(haystack.find_masked_bitmap(&needle, None, None, None, None), expected);
+
+        let mask = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(9, 9, |x, y| {
+                let v = icon.get_pixel(x, y)[3];
+                Rgba([v, v, v, 255])
+            })),
+            None,
+        );
+        let opaque = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(9, 9, |x, y| {
+                let mut pixel = *icon.get_pixel(x, y);
+                pixel[3] = 255;
+                pixel
+            })),
+            None,
+        );
+        assert_eq!(haystack.find_masked_bitmap(&opaque, Some(&mask), None, None, None), expected);
+        assert_eq!(haystack.count_of_masked_bitmap(&opaque, Some(&mask), None, None, None), 1);
+    }
+
+    #[test]
+    fn test_suppress_overlapping_matches() {
+        let at = |x: f64, y: f64, score: f64| Match {
+            rect: Rect::new(Point::new(x, y), Size::new(10.0, 10.0)),
+            score,
+            scale: 1.0,
+        };
+        let matches = [
+            at(0.0, 0.0, 0.9),
+            at(1.0, 0.0, 0.95),
+            at(0.0, 1.0, 0.9),
+            at(30.0, 0.0, 0.8),
+            at(31.0, 1.0, 0.8),
+        ];
+        let iou = suppress_overlapping_matches(&matches, Overlap::IntersectionOverUnion(0.5));
+        assert_eq!(iou, vec![matches[1], matches[3]]);
+        let distance = suppress_overlapping_matches(&matches, Overlap::MinDistance(1.5));
+        assert_eq!(distance, vec![matches[1], matches[3]]);
+        let close = suppress_overlapping_matches(&matches, Overlap::MinDistance(1.0));
+        assert_eq!(close, matches.to_vec());
+    }
+
+    #[test]
+    fn test_count_of_distinct_bitmap() {
+        // Soft-edged blobs, so that offsets next to each blob are within
+        // tolerance.
+        let blob = |x: u32, y: u32| {
+            let d = (x as f64 - 5.0).hypot(y as f64 - 5.0);
+            (255.0 * (1.0 - d / 8.0).max(0.0)) as u8
+        };
+        let haystack_img = RgbaImage::from_fn(60, 20, |x, y| {
+            let v = if x < 40 { blob(x % 20, y) } else { 0 };
+            Rgba([v, v, v, 255])
+        });
+        let needle_img = RgbaImage::from_fn(11, 11, |x, y| {
+            let v = blob(x, y);
+            Rgba([v, v, v, 255])
+        });
+        let haystack = Bitmap::new(DynamicImage::ImageRgba8(haystack_img), None);
+        let needle = Bitmap::new(DynamicImage::ImageRgba8(needle_img), None);
+        assert!(haystack.count_of_bitmap(&needle, Some(0.2), None, None) > 2);
+        let overlap = Overlap::IntersectionOverUnion(0.0);
+        let distinct =
+            haystack.find_every_distinct_bitmap(&needle, Some(0.2), overlap, None, None);
+        let origins: Vec<Point> = distinct.iter().map(|m| m.rect.origin).collect();
+        assert_eq!(origins, vec![Point::new(0.0, 0.0), Point::new(20.0, 0.0)]);
+        assert_eq!(
+            haystack.count_of_distinct_bitmap(&needle, Some(0.2), overlap, None, None),
+            2
+        );
+    }
+
+    #[test]
+    fn test_find_bitmap_with_mode() {
+        // The same button outline in a light and a dark theme.
+        let button = |fill: u8, border: u8| {
+            move |x: u32, y: u32| {
+                let on_border = (5..=20).contains(&x)
+                    && (4..=12).contains(&y)
+                    && (x == 5 || x == 20 || y == 4 || y == 12);
+                let v = if on_border { border } else { fill };
+                Rgba([v, v, v, 255])
+            }
+        };
+        let needle_img = RgbaImage::from_fn(26, 17, button(230, 40));
+        let haystack_img = RgbaImage::from_fn(60, 40, |x, y| {
+            if (17..43).contains(&x) && (11..28).contains(&y) {
+                button(20, 210)(x - 17, y - 11)
+            } else {
+                Rgba([20, 20, 20, 255])
+            }
+        });
+        let needle = Bitmap::new(DynamicImage::ImageRgba8(needle_img), None);
+        let haystack = Bitmap::new(DynamicImage::ImageRgba8(haystack_img), None);
+        assert_eq!(
+            haystack.find_bitmap_with_mode(&needle, MatchMode::Rgb, Some(0.2), None, None),
+            None
+        );
+        assert_eq!(
+            haystack.find_bitmap_with_mode(&needle, MatchMode::Edges, Some(0.1), None, None),
+            Some(Point::new(17.0, 11.0))
+        );
+        assert_eq!(
+            haystack
+                .find_every_bitmap_with_mode(&needle, MatchMode::Edges, Some(0.1), None, None)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_find_features() {
+        // Blocks of pseudo-random colors, giving plenty of distinct corners.
+        let texture = |x: f64, y: f64| {
+            let (column, row) = ((x / 6.0).floor() as i64, (y / 6.0).floor() as i64);
+            let v = ((column * 7919 + row * 104_729) ^ (column * row * 31)) as u32;
+            let v = v.wrapping_mul(2_654_435_761);
+            Rgba([(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, 255])
+        };
+        let needle_img = RgbaImage::from_fn(90, 72, |x, y| texture(f64::from(x), f64::from(y)));
+
+        // Scaled by 1.5, rotated by 10 degrees and with its bottom-right
+        // corner covered by another window.
+        let (scale, (sin, cos)) = (1.5, 10f64.to_radians().sin_cos());
+        let origin = Point::new(120.0, 60.0);
+        let project = |point: Point| {
+            Point::new(
+                origin.x + scale * (point.x * cos - point.y * sin),
+                origin.y + scale * (point.x * sin + point.y * cos),
+            )
+        };
+        let haystack_img = RgbaImage::from_fn(360, 280, |x, y| {
+            let (dx, dy) = (f64::from(x) - origin.x, f64::from(y) - origin.y);
+            let (nx, ny) = (
+                (dx * cos + dy * sin) / scale,
+                (-dx * sin + dy * cos) / scale,
+            );
+            if (200..300).contains(&x) && (150..240).contains(&y) {
+                Rgba([200, 200, 200, 255])
+            } else if (0.0..90.0).contains(&nx) && (0.0..72.0).contains(&ny) {
+                texture(nx, ny)
+            } else {
+                Rgba([40, 40, (x / 2) as u8, 255])
+            }
+        });
+        let needle = Bitmap::new(DynamicImage::ImageRgba8(needle_img), None);
+        let haystack = Bitmap::new(DynamicImage::ImageRgba8(haystack_img), None);
+
+        let found = haystack.find_features(&needle, None, None).unwrap();
+        let corners = [
+            Point::ZERO,
+            Point::new(90.0, 0.0),
+            Point::new(90.0, 72.0),
+            Point::new(0.0, 72.0),
+        ];
+        for (corner, expected) in found.quad.iter().zip(corners.map(project).iter()) {
+            assert!(
+                (corner.x - expected.x).hypot(corner.y - expected.y) < 4.0,
+                "{:?} != {:?}",
+                found.quad,
+                corners.map(project)
+            );
+        }
+
+        let plain = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(360, 280, Rgba([40, 40, 40, 255]))),
+            None,
+        );
+        assert!(plain.find_features(&needle, None, None).is_none());
+    }
+
+    #[test]
+    fn test_perceptual_hash() {
+        let algorithms = [
+            HashAlgorithm::Average,
+            HashAlgorithm::Difference,
+            HashAlgorithm::Perceptual,
+        ];
+        let gradient = |x: u32, y: u32| {
+            let v = ((x * 3 + y * 2) % 256) as u8;
+            Rgba([v, 255 - v, (x * y % 256) as u8, 255])
+        };
+        let original = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 48, gradient)),
+            None,
+        );
+        let mut edited = original.clone();
+        edited.image.put_pixel(10, 10, Rgba([255, 0, 0, 255]));
+        let resized = Bitmap::new(
+            original.image.resize_exact(128, 96, FilterType::Triangle),
+            Some(2.0),
+        );
+        let flipped = Bitmap::new(original.image.fliph(), None);
+
+        assert_eq!(ImageHash(0b1011).distance(ImageHash(0b0110)), 3);
+        for algorithm in algorithms.iter().copied() {
+            assert_eq!(
+                original.perceptual_hash(algorithm),
+                original.clone().perceptual_hash(algorithm)
+            );
+            assert!(original.perceptual_distance(&edited, algorithm) <= 2);
+            assert!(original.perceptual_distance(&resized, algorithm) <= 4);
+            assert!(original.perceptual_distance(&flipped, algorithm) > 16);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let original = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 20, Rgba([90, 120, 200, 255]))),
+            Some(2.0),
+        );
+        let mut updated = original.clone();
+        for (x, y) in [(4, 4), (5, 5), (6, 6), (7, 4), (30, 10), (31, 10)] {
+            updated.image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+        updated.image.put_pixel(20, 2, Rgba([92, 120, 200, 255]));
+
+        let diff = original.diff(&updated, Some(0.01));
+        assert_eq!(
+            diff.regions,
+            vec![
+                Rect::new(Point::new(2.0, 2.0), Size::new(2.0, 1.5)),
+                Rect::new(Point::new(15.0, 5.0), Size::new(1.0, 0.5)),
+            ]
+        );
+        assert!((diff.percent_changed - 6.0 / 8.0).abs() < 1e-9);
+        assert_eq!(diff.image.size, original.size);
+        assert_eq!(diff.image.image.get_pixel(5, 5), Rgba([255, 0, 0, 255]));
+        assert_ne!(diff.image.image.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+
+        assert_eq!(original.diff(&updated, None).regions.len(), 3);
+        assert!(original.diff(&original, None).is_empty());
+
+        // Merging two regions can make them touch a third that neither
+        // touched before.
+        let blank = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 255]))),
+            None,
+        );
+        let mut chained = blank.clone();
+        for (x, y) in [(0, 0), (1, 1), (2, 2), (3, 0), (4, 3)] {
+            chained.image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+        assert_eq!(
+            blank.diff(&chained, None).regions,
+            vec![Rect::new(Point::ZERO, Size::new(5.0, 4.0))]
+        );
+
+        // Isolated changes all over the bitmap stay separate.
+        let noise = RgbaImage::from_fn(600, 400, |x, y| {
+            if x % 2 == 0 && y % 2 == 0 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        let black = RgbaImage::from_pixel(600, 400, Rgba([0, 0, 0, 255]));
+        let noisy = Bitmap::new(DynamicImage::ImageRgba8(noise), None)
+            .diff(&Bitmap::new(DynamicImage::ImageRgba8(black), None), None);
+        assert_eq!(noisy.regions.len(), 300 * 200);
+    }
+
+    #[test]
+    fn test_find_color_regions() {
+        let badge = Rgba([220, 40, 40, 255]);
+        let image = RgbaImage::from_fn(30, 20, |x, y| {
+            let in_badge = (4..8).contains(&x) && (2..5).contains(&y);
+            let in_bar = (10..26).contains(&x) && y == 15;
+            if in_badge || in_bar || (x, y) == (8, 5) {
+                badge
+            } else if (x, y) == (12, 2) {
+                Rgba([215, 45, 40, 255])
+            } else {
+                Rgba([250, 250, 250, 255])
+            }
+        });
+        let bitmap = Bitmap::new(DynamicImage::ImageRgba8(image), Some(2.0));
+
+        let regions = bitmap.find_color_regions(badge, None, None);
+        assert_eq!(
+            regions,
+            vec![
+                ColorRegion {
+                    bounds: Rect::new(Point::new(2.0, 1.0), Size::new(2.5, 2.0)),
+                    pixel_count: 13,
+                    centroid: Point::new(74.0 / 13.0 / 2.0 + 0.25, 41.0 / 13.0 / 2.0 + 0.25),
+                },
+                ColorRegion {
+                    bounds: Rect::new(Point::new(5.0, 7.5), Size::new(8.0, 0.5)),
+                    pixel_count: 16,
+                    centroid: Point::new(9.0, 7.75),
+                },
+            ]
+        );
+        assert_eq!(bitmap.find_color_regions(badge, Some(0.1), None).len(), 3);
+
+        let rect = Rect::new(Point::new(6.0, 6.0), Size::new(4.0, 2.0));
+        let regions = bitmap.find_color_regions(badge, None, Some(rect));
+        assert_eq!(regions.len(), 1);
+        assert_eq!(
+            regions[0].bounds,
+            Rect::new(Point::new(6.0, 7.5), Size::new(4.0, 0.5))
+        );
+    }
+
+    #[test]
+    fn test_color_metrics() {
+        let metrics = [
+            ColorMetric::Rgb,
+            ColorMetric::Cie76,
+            ColorMetric::Ciede2000,
+            ColorMetric::ChannelMax,
+        ];
+        let (black, white) = (Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255]));
+        for metric in metrics {
+            assert_eq!(metric.distance(black, black), 0.0);
+            assert!((metric.distance(black, white) - 1.0).abs() < 1e-3);
+            assert!(metric.matches(black, white, 1.0));
+            assert!(!metric.matches(black, Rgba([0, 0, 0, 254]), 0.0));
+        }
+        assert_eq!(
+            ColorMetric::ChannelMax.distance(black, Rgba([51, 10, 0, 255])),
+            0.2
+        );
+
+        // Adding the same amount of red is barely visible on a saturated
+        // green, but obvious on gray.
+        let green = (Rgba([0, 200, 0, 255]), Rgba([30, 200, 0, 255]));
+        let gray = (Rgba([100, 100, 100, 255]), Rgba([130, 100, 100, 255]));
+        assert_eq!(
+            ColorMetric::Rgb.distance(green.0, green.1),
+            ColorMetric::Rgb.distance(gray.0, gray.1)
+        );
+        for metric in [ColorMetric::Cie76, ColorMetric::Ciede2000] {
+            assert!(3.0 * metric.distance(green.0, green.1) < metric.distance(gray.0, gray.1));
+        }
+
+        let dark = (Rgba([10, 10, 10, 255]), Rgba([20, 20, 20, 255]));
+        let light = (Rgba([235, 235, 235, 255]), Rgba([245, 245, 245, 255]));
+
+        let image = RgbaImage::from_fn(12, 8, |x, y| {
+            if (x, y) == (3, 2) {
+                Rgba([240, 240, 240, 255])
+            } else if (6..9).contains(&x) && (4..7).contains(&y) {
+                Rgba([15, 15, 15, 255])
+            } else {
+                Rgba([128, 128, 128, 255])
+            }
+        });
+        let haystack = Bitmap::new(DynamicImage::ImageRgba8(image), None);
+        let lab = ColorMetric::Ciede2000;
+        assert_eq!(
+            haystack.find_color_with_metric(light.1, lab, Some(0.05), None, None),
+            Some(Point::new(3.0, 2.0))
+        );
+        assert_eq!(
+            haystack.find_color_with_metric(dark.1, lab, Some(0.01), None, None),
+            None
+        );
+
+        let needle = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 3, dark.1)),
+            None,
+        );
+        assert_eq!(
+            haystack.find_bitmap_with_metric(&needle, ColorMetric::ChannelMax, Some(0.02), None, None),
+            Some(Point::new(6.0, 4.0))
+        );
+        assert_eq!(
+            haystack.find_bitmap_with_metric(&needle, lab, Some(0.01), None, None),
+            None
+        );
+        let similar = Bitmap::new(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 3, Rgba([21, 20, 20, 255]))),
+            None,
+        );
+        assert!(!needle.bitmap_eq_with_metric(&similar, ColorMetric::Rgb, None));
+        assert!(needle.bitmap_eq_with_metric(&similar, lab, Some(0.01)));
+    }
+
+    #[test]
+    fn test_find_color_in_range() {
+        let shades = [
+            Rgba([40, 180, 60, 255]),
+            Rgba([90, 200, 110, 255]),
+            Rgba([20, 120, 30, 255]),
+        ];
+        let image = RgbaImage::from_fn(10, 6, |x, y| match (x, y) {
+            (2, 1) => shades[0],
+            (7, 3) => shades[1],
+            (4, 5) => shades[2],
+            (5, 0) => Rgba([150, 170, 150, 255]),
+            (0, 4) => Rgba([230, 40, 50, 255]),
+            _ => Rgba([245, 245, 245, 255]),
+        });
+        let bitmap = Bitmap::new(DynamicImage::ImageRgba8(image), None);
+
+        let green = ColorRange::hsv(100.0..=140.0, 0.5..=1.0, 0.0..=1.0).unwrap();
+        assert!(shades.iter().all(|&shade| green.contains(shade)));
+        assert_eq!(
+            bitmap.find_color_in_range(green, None, None),
+            Some(Point::new(2.0, 1.0))
+        );
+        assert_eq!(
+            bitmap.find_every_color_in_range(green, None, None),
+            vec![
+                Point::new(2.0, 1.0),
+                Point::new(4.0, 5.0),
+                Point::new(7.0, 3.0)
+            ]
+        );
+        assert_eq!(bitmap.count_of_color_in_range(green, None, None), 3);
+        assert_eq!(
+            bitmap.count_of_color_in_range(
+                ColorRange::hsv(100.0..=140.0, 0.5..=1.0, 0.6..=1.0).unwrap(),
+                None,
+                None
+            ),
+            2
+        );
+        assert_eq!(
+            bitmap.count_of_color_in_range(
+                ColorRange::hsv(100.0..=140.0, 0.0..=1.0, 0.0..=1.0).unwrap(),
+                None,
+                None
+            ),
+            4
+        );
+
+        let red = ColorRange::hsv(340.0..=20.0, 0.5..=1.0, 0.0..=1.0).unwrap();
+        assert_eq!(
+            bitmap.find_color_in_range(red, None, None),
+            Some(Point::new(0.0, 4.0))
+        );
+
+        let light = ColorRange::rgb(Rgb([200, 200, 200]), Rgb([255, 255, 255]));
+        assert_eq!(bitmap.count_of_color_in_range(light, None, None), 55);
+        assert_eq!(
+            bitmap.find_color_in_range(
+                light,
+                Some(Rect::new(Point::new(2.0, 1.0), Size::new(1.0, 1.0))),
+                Some(Point::new(2.0, 1.0))
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_color_range_hsv_bounds() {
+        assert!(ColorRange::hsv(0.0..=360.0, 0.0..=1.0, 0.0..=1.0).is_some());
+        assert!(ColorRange::hsv(340.0..=20.0, 0.5..=1.0, 0.0..=1.0).is_some());
+        assert!(ColorRange::hsv(-10.0..=20.0, 0.0..=1.0, 0.0..=1.0).is_none());
+        assert!(ColorRange::hsv(340.0..=380.0, 0.0..=1.0, 0.0..=1.0).is_none());
+        assert!(ColorRange::hsv(f64::NAN..=20.0, 0.0..=1.0, 0.0..=1.0).is_none());
+        assert!(ColorRange::hsv(0.0..=20.0, 0.8..=0.2, 0.0..=1.0).is_none());
+        assert!(ColorRange::hsv(0.0..=20.0, 0.0..=1.0, 0.0..=1.5).is_none());
+    }
+
+    #[test]
+    fn test_png_scale() {
+        let encode = |pixels_per_meter: u32| {
+            let mut bytes = Vec::new();
+            let mut encoder = png::Encoder::new(&mut bytes, 1, 1);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_pixel_dims(Some(png::PixelDimensions {
+                xppu: pixels_per_meter,
+                yppu: pixels_per_meter,
+                unit: png::Unit::Meter,
+            }));
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0, 0, 0, 255]).unwrap();
+            writer.finish().unwrap();
+            bytes
+        };
+        assert_eq!(png_scale(&encode(5669)).unwrap(), Some(2.0));
+        assert_eq!(png_scale(&encode(2835)).unwrap(), Some(1.0));
+        assert_eq!(png_scale(&encode(3780)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_pixels() {
+        let rgb = RgbImage::from_fn(4, 3, |x, y| Rgb([x as u8, y as u8, 9]));
+        let bitmap = Bitmap::new(DynamicImage::ImageRgb8(rgb), None);
+        assert!(bitmap.image.as_rgba8().is_some());
+
+        let pixels = bitmap.pixels();
+        assert_eq!((pixels.width(), pixels.height(), pixels.stride()), (4, 3, 16));
+        assert_eq!(pixels.get(3, 2), Rgba([3, 2, 9, 255]));
+        assert_eq!(pixels.row(1)[4..8], [1, 1, 9, 255]);
+
+        let view = pixels.view(1, 1, 2, 2);
+        assert_eq!((view.width(), view.height(), view.stride()), (2, 2, 16));
+        assert_eq!(view.get(0, 0), Rgba([1, 1, 9, 255]));
+        assert_eq!(view.rows().map(|row| row.len()).collect::<Vec<_>>(), [8, 8]);
+        assert_eq!(view.get(1, 1), bitmap.get_pixel(Point::new(2.0, 2.0)));
+
+        // Images replaced after construction are converted rather than
+        // rejected.
+        let rgb = RgbImage::from_fn(4, 3, |x, y| Rgb([x as u8 * 60, y as u8 * 80, 0]));
+        let mut gray = Bitmap::new(DynamicImage::ImageRgb8(rgb), None);
+        gray.image = gray.image.grayscale();
+        assert!(gray.image.as_rgba8().is_none());
+        assert_eq!(gray.pixels().get(3, 2), gray.image.get_pixel(3, 2));
+        let color = gray.image.get_pixel(1, 1);
+        assert_eq!(
+            gray.find_color(color, None, None, None),
+            Some(Point::new(1.0, 1.0))
+        );
+        let needle = Bitmap::new(gray.image.crop_imm(1, 1, 2, 2), None);
+        assert_eq!(
+            gray.find_bitmap(&needle, None, None, None),
+            Some(Point::new(1.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn test_rescaled() {
+        let image = RgbaImage::from_fn(5, 3, |x, y| Rgba([x as u8 * 50, y as u8 * 100, 0, 255]));
+        let bitmap = Bitmap::new(DynamicImage::ImageRgba8(image), None);
+
+        let doubled = bitmap.rescaled(2.0, ResampleFilter::Nearest);
+        assert_eq!(doubled.scale, 2.0);
+        assert_eq!(doubled.image.dimensions(), (10, 6));
+        assert_eq!(doubled.size, bitmap.size);
+        assert_eq!(doubled.image.get_pixel(7, 5), Rgba([150, 200, 0, 255]));
+        assert_eq!(doubled.rescaled(1.0, ResampleFilter::Nearest), bitmap);
+
+        for filter in [ResampleFilter::Bilinear, ResampleFilter::Lanczos] {
+            let rescaled = doubled.rescaled(1.5, filter);
+            assert_eq!(rescaled.image.dimensions(), (8, 5));
+            assert_eq!(rescaled.scale, 1.5);
+            assert!((rescaled.size.width - 8.0 / 1.5).abs() < 1e-9);
+        }
+        assert_eq!(bitmap.rescaled(0.1, ResampleFilter::Bilinear).image.dimensions(), (1, 1));
+        assert_eq!(bitmap.rescaled(1.0, ResampleFilter::Lanczos), bitmap);
+    }
+
+    #[test]
+    fn test_drawing() {
+        let white = Rgba([255, 255, 255, 255]);
+        let red = Rgba([255, 0, 0, 255]);
+        let blank = Bitmap::new(DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 20, white)), None);
+        let pixel = |bitmap: &Bitmap, x: f64, y: f64| bitmap.get_pixel(Point::new(x, y));
+
+        let mut bitmap = blank.clone();
+        bitmap.draw_rect(Rect::new(Point::new(2.0, 3.0), Size::new(10.0, 8.0)), red, Some(2.0));
+        for (x, y) in [(2.0, 3.0), (11.0, 10.0), (3.0, 6.0), (10.0, 6.0), (7.0, 4.0)] {
+            assert_eq!(pixel(&bitmap, x, y), red);
+        }
+        for (x, y) in [(4.0, 5.0), (9.0, 8.0), (12.0, 6.0), (1.0, 3.0)] {
+            assert_eq!(pixel(&bitmap, x, y), white);
+        }
+
+        let mut bitmap = blank.clone();
+        bitmap.draw_crosshair(Point::new(38.0, 10.0), red, Some(3.0));
+        for (x, y) in [(38.0, 10.0), (35.0, 10.0), (39.0, 10.0), (38.0, 7.0), (38.0, 13.0)] {
+            assert_eq!(pixel(&bitmap, x, y), red);
+        }
+        assert_eq!(pixel(&bitmap, 34.0, 10.0), white);
+        assert_eq!(pixel(&bitmap, 37.0, 9.0), white);
+
+        let mut bitmap = blank.clone();
+        bitmap.fill_rect(Rect::new(Point::new(0.0, 0.0), Size::new(2.0, 2.0)), Rgba([0, 0, 0, 128]));
+        assert_eq!(pixel(&bitmap, 1.0, 1.0), Rgba([127, 127, 127, 255]));
+        assert_eq!(pixel(&bitmap, 2.0, 1.0), white);
+
+        let mut bitmap = blank.clone();
+        let bounds = bitmap.draw_label(Point::new(1.0, 2.0), "A1", red, Some(Rgba([0, 0, 0, 255])));
+        assert_eq!(bounds, Rect::new(Point::new(1.0, 2.0), Size::new(13.0, 9.0)));
+        // The top of "A" is set, its top-left corner is not.
+        assert_eq!(pixel(&bitmap, 3.0, 3.0), red);
+        assert_eq!(pixel(&bitmap, 2.0, 3.0), Rgba([0, 0, 0, 255]));
+        assert_eq!(pixel(&bitmap, 14.0, 2.0), white);
+
+        let scaled = Bitmap::new(blank.image.clone(), Some(2.0));
+        let mut bitmap = scaled.clone();
+        bitmap.draw_label(Point::new(0.0, 0.0), "-", red, None);
+        assert_eq!(bitmap.image.get_pixel(2, 8), red);
+        assert_eq!(bitmap.image.get_pixel(3, 9), red);
+        assert_eq!(bitmap.image.get_pixel(2, 7), white);
+
+        let matches = [Rect::new(Point::new(20.0, 12.0), Size::new(4.0, 4.0))];
+        let annotated = blank.annotated(None, Some(Point::new(5.0, 5.0)), &matches);
+        assert_eq!(annotated.size, blank.size);
+        assert_eq!(pixel(&annotated, 0.0, 0.0), SEARCH_RECT_COLOR);
+        assert_eq!(pixel(&annotated, 5.0, 5.0), START_POINT_COLOR);
+        assert_eq!(pixel(&annotated, 23.0, 15.0), MATCH_COLOR);
+        assert_eq!(pixel(&blank, 23.0, 15.0), white);
+    }
+
     quickcheck! {
         fn finds_cropped_bitmap(haystack: Bitmap) -> TestResult {
             if haystack.size.width < 2.0 {
@@ -734,6 +3846,51 @@ mod tests {
         }
     }
 
+    quickcheck! {
+        fn scores_cropped_bitmap(haystack: Bitmap) -> TestResult {
+            if haystack.size.width < 2.0 {
+                return TestResult::discard();
+            }
+
+            let mut rng = rng();
+            let offset_percentage: f64 = rng.random_range(0.0..1.0);
+            let cropped_size = (haystack.size.width / 2.0).round();
+            let offset_pt = Point::new(
+                (haystack.size.width - cropped_size) * offset_percentage,
+                (haystack.size.height - cropped_size) * offset_percentage
+            ).round();
+            let needle = haystack.clone().cropped(Rect::new(
+                offset_pt,
+                Size::new(cropped_size, cropped_size)
+            )).unwrap();
+            let best = haystack.find_best_bitmap(&needle, None);
+            let matches = haystack.find_every_scored_bitmap(&needle, 0.999, None);
+            TestResult::from_bool(best.is_some_and(|m| m.score > 0.999) &&
+                                  matches.iter().any(|m| m.rect.origin == offset_pt) &&
+                                  matches.windows(2).all(|w| w[0].score >= w[1].score))
+        }
+    }
+
+    quickcheck! {
+        fn finds_first_of_every_bitmap(tile: Bitmap) -> TestResult {
+            if tile.size.width <= 2.0 {
+                return TestResult::discard();
+            }
+            let haystack_img = DynamicImage::ImageRgba8(RgbaImage::from_fn(
+                tile.image.width() * 3,
+                tile.image.height() * 3,
+                |x, y| tile.image.get_pixel(x % tile.image.width(), y % tile.image.height())
+            ));
+            let haystack = Bitmap::new(haystack_img, Some(tile.scale));
+            let every = haystack.find_every_bitmap(&tile, None, None, None);
+            let count = haystack.count_of_bitmap(&tile, None, None, None);
+            TestResult::from_bool(
+                haystack.find_bitmap(&tile, None, None, None) == every.first().copied() &&
+                    count == every.len() as u64
+            )
+        }
+    }
+
     quickcheck! {
         fn skips_inverted_bitmap(haystack: Bitmap) -> TestResult {
             if haystack.size.width == 0.0 {
@@ -748,6 +3905,31 @@ mod tests {
         }
     }
 
+    quickcheck! {
+        fn pyramid_search_matches_direct_search(tile: Bitmap, tolerance: u8) -> TestResult {
+            if tile.size.width <= 2.0 {
+                return TestResult::discard();
+            }
+            let tolerance = f64::from(tolerance % 4) / 16.0;
+            let haystack_img = DynamicImage::ImageRgba8(RgbaImage::from_fn(
+                tile.image.width() * 3 + 1,
+                tile.image.height() * 2 + 2,
+                |x, y| tile.image.get_pixel(x % tile.image.width(), y % tile.image.height())
+            ));
+            let haystack = Bitmap::new(haystack_img, Some(tile.scale));
+            let area = haystack.search_area(None, None);
+            let direct: Vec<Point> = area
+                .columns()
+                .flat_map(|x| area.column_rows(x).map(move |y| Point::new(x as f64, y as f64)))
+                .filter(|&pt| haystack.is_needle_at(pt, &tile, Some(tolerance), ColorMetric::Rgb))
+                .map(|pt| pt.scaled(haystack.scale).round())
+                .collect();
+            TestResult::from_bool(
+                haystack.find_every_bitmap(&tile, Some(tolerance), None, None) == direct
+            )
+        }
+    }
+
     quickcheck! {
         fn count_of_tiled_bitmap(tile: Bitmap) -> TestResult {
             if tile.size.width <= 2.0 {
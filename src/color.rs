@@ -0,0 +1,208 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! Color space conversions and perceptual color differences.
+use image::Rgba;
+
+/// A color in the CIELAB color space, under a D65 white point.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Lab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+impl Lab {
+    /// Converts an sRGB color to CIELAB, ignoring alpha.
+    pub fn from_rgba(color: Rgba<u8>) -> Lab {
+        let linear = |channel: u8| {
+            let value = f64::from(channel) / 255.0;
+            if value <= 0.04045 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        let (r, g, b) = (linear(color[0]), linear(color[1]), linear(color[2]));
+
+        // Relative to the D65 reference white.
+        let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+        let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+        let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+
+        let f = |t: f64| {
+            const DELTA: f64 = 6.0 / 29.0;
+            if t > DELTA * DELTA * DELTA {
+                t.cbrt()
+            } else {
+                t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+            }
+        };
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+}
+
+/// A color in the HSV color space.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hsv {
+    /// Hue in degrees, from 0 up to 360. Grays have a hue of 0.
+    pub hue: f64,
+
+    /// Saturation from 0 to 1.
+    pub saturation: f64,
+
+    /// Value from 0 to 1.
+    pub value: f64,
+}
+
+impl Hsv {
+    /// Converts an RGB color to HSV, ignoring alpha.
+    pub fn from_rgba(color: Rgba<u8>) -> Hsv {
+        let (r, g, b) = (
+            f64::from(color[0]) / 255.0,
+            f64::from(color[1]) / 255.0,
+            f64::from(color[2]) / 255.0,
+        );
+        let max = r.max(g).max(b);
+        let delta = max - r.min(g).min(b);
+        let hue = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        Hsv {
+            hue,
+            saturation: if max == 0.0 { 0.0 } else { delta / max },
+            value: max,
+        }
+    }
+}
+
+/// Returns the CIE76 color difference, i.e. the Euclidean distance between
+/// two colors in CIELAB.
+pub fn cie76(c1: Lab, c2: Lab) -> f64 {
+    ((c1.l - c2.l).powi(2) + (c1.a - c2.a).powi(2) + (c1.b - c2.b).powi(2)).sqrt()
+}
+
+/// Returns the CIEDE2000 color difference, with all weighting factors set
+/// to 1.
+pub fn ciede2000(c1: Lab, c2: Lab) -> f64 {
+    let pow7 = |v: f64| v.powi(7);
+    let c_bar = (c1.a.hypot(c1.b) + c2.a.hypot(c2.b)) / 2.0;
+    let g = 0.5 * (1.0 - (pow7(c_bar) / (pow7(c_bar) + pow7(25.0))).sqrt());
+    let (a1, a2) = (c1.a * (1.0 + g), c2.a * (1.0 + g));
+    let (chroma1, chroma2) = (a1.hypot(c1.b), a2.hypot(c2.b));
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let (h1, h2) = (hue(c1.b, a1), hue(c2.b, a2));
+
+    let delta_l = c2.l - c1.l;
+    let delta_c = chroma2 - chroma1;
+    let delta_h = if chroma1 * chroma2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 <= h1 {
+        h2 - h1 + 360.0
+    } else {
+        h2 - h1 - 360.0
+    };
+    let delta_big_h = 2.0 * (chroma1 * chroma2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+    let l_bar = (c1.l + c2.l) / 2.0;
+    let chroma_bar = (chroma1 + chroma2) / 2.0;
+    let h_bar = if chroma1 * chroma2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+    let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+    let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(chroma_bar) / (pow7(chroma_bar) + pow7(25.0))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * chroma_bar;
+    let s_h = 1.0 + 0.015 * chroma_bar * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    ((delta_l / s_l).powi(2)
+        + (delta_c / s_c).powi(2)
+        + (delta_big_h / s_h).powi(2)
+        + r_t * (delta_c / s_c) * (delta_big_h / s_h))
+        .sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::{Hsv, Lab, cie76, ciede2000};
+    use image::Rgba;
+
+    #[test]
+    fn test_lab_from_rgba() {
+        let white = Lab::from_rgba(Rgba([255, 255, 255, 255]));
+        assert!((white.l - 100.0).abs() < 1e-3 && white.a.abs() < 1e-3 && white.b.abs() < 1e-3);
+        let red = Lab::from_rgba(Rgba([255, 0, 0, 255]));
+        assert!((red.l - 53.24).abs() < 0.01);
+        assert!((red.a - 80.09).abs() < 0.01);
+        assert!((red.b - 67.20).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_hsv_from_rgba() {
+        let hsv = |r, g, b| {
+            let hsv = Hsv::from_rgba(Rgba([r, g, b, 255]));
+            (hsv.hue.round(), (hsv.saturation * 100.0).round(), (hsv.value * 100.0).round())
+        };
+        assert_eq!(hsv(255, 0, 0), (0.0, 100.0, 100.0));
+        assert_eq!(hsv(0, 128, 0), (120.0, 100.0, 50.0));
+        assert_eq!(hsv(255, 0, 128), (330.0, 100.0, 100.0));
+        assert_eq!(hsv(51, 102, 153), (210.0, 67.0, 60.0));
+        assert_eq!(hsv(128, 128, 128), (0.0, 0.0, 50.0));
+        assert_eq!(hsv(0, 0, 0), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_color_differences() {
+        // Reference pairs from Sharma, Wu and Dalal (2005).
+        let pairs = [
+            ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+            ((50.0, -1.0, 2.0), (50.0, 0.0, 0.0), 2.3669),
+            ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+            ((2.0776, 0.0795, -1.135), (0.9033, -0.0636, -0.5514), 0.9082),
+        ];
+        for ((l1, a1, b1), (l2, a2, b2), expected) in pairs {
+            let c1 = Lab { l: l1, a: a1, b: b1 };
+            let c2 = Lab { l: l2, a: a2, b: b2 };
+            assert!((ciede2000(c1, c2) - expected).abs() < 1e-4);
+            assert!((ciede2000(c2, c1) - expected).abs() < 1e-4);
+        }
+
+        let black = Lab::from_rgba(Rgba([0, 0, 0, 255]));
+        let white = Lab::from_rgba(Rgba([255, 255, 255, 255]));
+        assert!((cie76(black, white) - 100.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! Normalized cross-correlation template matching, computed in the frequency
+//! domain.
+//!
+//! The haystack is correlated a tile at a time, so memory use stays bounded
+//! for large haystacks, and transforms are padded to sizes with only small
+//! prime factors. The three channels are packed into two complex transforms
+//! instead of three: red and green share one as its real and imaginary
+//! parts, whose correlations add up in the real part of the result.
+use crate::bitmap::PixelView;
+use crate::summed_area::SummedArea;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::sync::Arc;
+
+/// Variance below which a window is considered flat.
+const FLAT_EPSILON: f64 = 1e-6;
+
+/// Precision scores are rounded to, so that identical occurrences of a
+/// needle tie instead of being ordered by floating point noise.
+const SCORE_PRECISION: f64 = 1e9;
+
+/// Bounds on the width and height of a tile, unless the haystack is smaller
+/// or the needle larger.
+const MIN_TILE_SIZE: usize = 512;
+const MAX_TILE_SIZE: usize = 2048;
+
+/// Number of columns gathered into contiguous memory for each batch of
+/// column transforms.
+const COLUMN_BATCH: usize = 16;
+
+/// An RGB image stored as one plane per channel.
+pub struct Planes {
+    pub width: usize,
+    pub height: usize,
+    pub channels: [Vec<u8>; 3],
+}
+
+impl Planes {
+    /// Creates planes from the red, green and blue channels of `pixels`.
+    pub fn from_pixels(pixels: &PixelView) -> Planes {
+        let (width, height) = (pixels.width() as usize, pixels.height() as usize);
+        let mut channels = [
+            Vec::with_capacity(width * height),
+            Vec::with_capacity(width * height),
+            Vec::with_capacity(width * height),
+        ];
+        for row in pixels.rows() {
+            for pixel in row.chunks_exact(4) {
+                for (plane, &value) in channels.iter_mut().zip(pixel) {
+                    plane.push(value);
+                }
+            }
+        }
+        Planes {
+            width,
+            height,
+            channels,
+        }
+    }
+
+    #[inline]
+    fn get(&self, channel: usize, x: usize, y: usize) -> f64 {
+        f64::from(self.channels[channel][y * self.width + x])
+    }
+}
+
+/// Calls `scored` with the zero-normalized cross-correlation of `needle` at
+/// every offset `(x, y)` where it fits entirely inside `haystack`, clamped
+/// to the range 0 to 1. Offsets are visited a tile at a time rather than in
+/// row or column order.
+///
+/// Channels are combined as a single correlation, so a needle matches only
+/// where all three channels vary together.
+///
+/// Scores are rounded to nine decimal places.
+pub fn ncc_scores<F: FnMut(usize, usize, f64)>(haystack: &Planes, needle: &Planes, mut scored: F) {
+    assert!(needle.width <= haystack.width && needle.height <= haystack.height);
+    let count = (needle.width * needle.height) as f64;
+    let tile_width = tile_size(needle.width, haystack.width);
+    let tile_height = tile_size(needle.height, haystack.height);
+    let mut correlator = Correlator::new(tile_width, tile_height);
+
+    // Correlating a window with the centered needle gives their covariance
+    // without having to subtract the window's mean.
+    let mut needle_means = [0.0; 3];
+    let mut needle_variance = 0.0;
+    let centered: Vec<Vec<f64>> = (0..3)
+        .map(|channel| {
+            let plane = &needle.channels[channel];
+            let mean = plane.iter().map(|&v| f64::from(v)).sum::<f64>() / count;
+            let centered: Vec<f64> = plane.iter().map(|&v| f64::from(v) - mean).collect();
+            needle_variance += centered.iter().map(|v| v * v).sum::<f64>();
+            needle_means[channel] = mean;
+            centered
+        })
+        .collect();
+    let at = |x: usize, y: usize| y * needle.width + x;
+    let kernels = [
+        correlator.spectrum(needle.width, needle.height, |x, y| {
+            Complex::new(centered[0][at(x, y)], centered[1][at(x, y)])
+        }),
+        correlator.spectrum(needle.width, needle.height, |x, y| {
+            Complex::new(centered[2][at(x, y)], 0.0)
+        }),
+    ];
+    let needle_flat = needle_variance <= FLAT_EPSILON * count;
+
+    // Each tile scores the offsets whose windows lie entirely inside it, so
+    // consecutive tiles overlap by the size of the needle.
+    let step_x = tile_width - needle.width + 1;
+    let step_y = tile_height - needle.height + 1;
+    let out_width = haystack.width - needle.width + 1;
+    let out_height = haystack.height - needle.height + 1;
+    for tile_y in (0..out_height).step_by(step_y) {
+        for tile_x in (0..out_width).step_by(step_x) {
+            let width = (haystack.width - tile_x).min(tile_width);
+            let height = (haystack.height - tile_y).min(tile_height);
+            let columns = (out_width - tile_x).min(step_x);
+            let rows = (out_height - tile_y).min(step_y);
+            let value =
+                |channel: usize, x: usize, y: usize| haystack.get(channel, tile_x + x, tile_y + y);
+            let numerator = correlator.correlate(
+                width,
+                height,
+                |x, y| Complex::new(value(0, x, y), value(1, x, y)),
+                |x, y| Complex::new(value(2, x, y), 0.0),
+                &kernels,
+                rows,
+            );
+            let sums: Vec<SummedArea> = (0..3)
+                .map(|channel| SummedArea::new(width, height, |x, y| value(channel, x, y)))
+                .collect();
+            // Only the total of the squares over all channels is needed.
+            let squares = SummedArea::new(width, height, |x, y| {
+                (0..3).map(|channel| value(channel, x, y).powi(2)).sum()
+            });
+
+            for y in 0..rows {
+                for x in 0..columns {
+                    let mut variance = squares.window(x, y, needle.width, needle.height);
+                    let mut means = [0.0; 3];
+                    for channel in 0..3 {
+                        let sum = sums[channel].window(x, y, needle.width, needle.height);
+                        variance -= sum * sum / count;
+                        means[channel] = sum / count;
+                    }
+                    let variance = variance.max(0.0);
+
+                    let window_flat = variance <= FLAT_EPSILON * count;
+                    let score = if needle_flat || window_flat {
+                        // Correlation is undefined for flat regions; fall back
+                        // to comparing mean colors so that flat needles can
+                        // still match.
+                        if needle_flat && window_flat {
+                            let delta: f64 = means
+                                .iter()
+                                .zip(needle_means.iter())
+                                .map(|(a, b)| (a - b).abs())
+                                .sum();
+                            1.0 - delta / (3.0 * 255.0)
+                        } else {
+                            0.0
+                        }
+                    } else {
+                        let covariance = numerator[y * tile_width + x];
+                        (covariance / (variance * needle_variance).sqrt()).clamp(0.0, 1.0)
+                    };
+                    scored(
+                        tile_x + x,
+                        tile_y + y,
+                        (score * SCORE_PRECISION).round() / SCORE_PRECISION,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Returns the length of tiles along an axis: a few times the needle's
+/// length, so that little work is repeated where tiles overlap, with offsets
+/// split evenly between tiles and rounded up to a product of 2, 3 and 5 so
+/// that transforms are fast.
+fn tile_size(needle: usize, haystack: usize) -> usize {
+    let offsets = haystack - needle + 1;
+    let target = (needle * 6)
+        .clamp(MIN_TILE_SIZE, MAX_TILE_SIZE)
+        .max(needle * 2);
+    let tiles = offsets.div_ceil(target - needle + 1);
+    let mut size = offsets.div_ceil(tiles) + needle - 1;
+    loop {
+        let mut rest = size;
+        for factor in [2, 3, 5] {
+            while rest.is_multiple_of(factor) {
+                rest /= factor;
+            }
+        }
+        if rest == 1 {
+            return size;
+        }
+        size += 1;
+    }
+}
+
+/// Computes 2D cross-correlations of tiles of a fixed size.
+struct Correlator {
+    width: usize,
+    height: usize,
+    rows: [Arc<dyn Fft<f64>>; 2],
+    columns: [Arc<dyn Fft<f64>>; 2],
+    buffers: [Vec<Complex<f64>>; 2],
+    batch: Vec<Complex<f64>>,
+    scratch: Vec<Complex<f64>>,
+}
+
+impl Correlator {
+    fn new(width: usize, height: usize) -> Correlator {
+        let mut planner = FftPlanner::new();
+        let rows = [
+            planner.plan_fft_forward(width),
+            planner.plan_fft_inverse(width),
+        ];
+        let columns = [
+            planner.plan_fft_forward(height),
+            planner.plan_fft_inverse(height),
+        ];
+        let scratch_len = rows
+            .iter()
+            .chain(columns.iter())
+            .map(|fft| fft.get_inplace_scratch_len())
+            .max()
+            .unwrap_or(0);
+        let zero = Complex::new(0.0, 0.0);
+        Correlator {
+            width,
+            height,
+            rows,
+            columns,
+            buffers: [vec![zero; width * height], vec![zero; width * height]],
+            batch: vec![zero; COLUMN_BATCH * height],
+            scratch: vec![zero; scratch_len],
+        }
+    }
+
+    /// Returns the conjugated spectrum of a kernel of the given size, padded
+    /// with zeros to the size of a tile.
+    fn spectrum<F: Fn(usize, usize) -> Complex<f64>>(
+        &mut self,
+        width: usize,
+        height: usize,
+        value: F,
+    ) -> Vec<Complex<f64>> {
+        let mut data = vec![Complex::new(0.0, 0.0); self.width * self.height];
+        self.fill(&mut data, width, height, value);
+        self.transform(&mut data, false, height);
+        data.iter().map(|c| c.conj()).collect()
+    }
+
+    /// Returns the sum of the real parts of `first` and `second` correlated
+    /// with their respective `kernels`, i.e. `sum(image[x + i, y + j] *
+    /// kernel[i, j])` for every `(x, y)` in the first `rows` rows, laid out
+    /// with the width of a tile. Values where a kernel would extend past the
+    /// tile edge wrap around and should be ignored.
+    fn correlate<F, G>(
+        &mut self,
+        width: usize,
+        height: usize,
+        first: F,
+        second: G,
+        kernels: &[Vec<Complex<f64>>; 2],
+        rows: usize,
+    ) -> Vec<f64>
+    where
+        F: Fn(usize, usize) -> Complex<f64>,
+        G: Fn(usize, usize) -> Complex<f64>,
+    {
+        let [mut a, mut b] = std::mem::take(&mut self.buffers);
+        self.fill(&mut a, width, height, first);
+        self.fill(&mut b, width, height, second);
+        self.transform(&mut a, false, height);
+        self.transform(&mut b, false, height);
+        for ((a, b), (k1, k2)) in a
+            .iter_mut()
+            .zip(b.iter())
+            .zip(kernels[0].iter().zip(kernels[1].iter()))
+        {
+            *a = *a * k1 + b * k2;
+        }
+        self.transform(&mut a, true, rows);
+
+        let norm = (self.width * self.height) as f64;
+        let correlation = a[..rows * self.width].iter().map(|c| c.re / norm).collect();
+        self.buffers = [a, b];
+        correlation
+    }
+
+    /// Writes `value` at each point of a region of the given size in the
+    /// top left of `data`, and zeros everywhere else.
+    fn fill<F: Fn(usize, usize) -> Complex<f64>>(
+        &self,
+        data: &mut [Complex<f64>],
+        width: usize,
+        height: usize,
+        value: F,
+    ) {
+        for (y, row) in data.chunks_exact_mut(self.width).enumerate() {
+            for (x, out) in row.iter_mut().enumerate() {
+                *out = if x < width && y < height {
+                    value(x, y)
+                } else {
+                    Complex::new(0.0, 0.0)
+                };
+            }
+        }
+    }
+
+    /// Transforms `data` in place. Only the first `rows` rows are read by a
+    /// forward transform, the rest being zero, and only the first `rows` rows
+    /// are written by an inverse one.
+    fn transform(&mut self, data: &mut [Complex<f64>], inverse: bool, rows: usize) {
+        let len = rows * self.width;
+        if inverse {
+            self.transform_columns(data, true);
+            self.rows[1].process_with_scratch(&mut data[..len], &mut self.scratch);
+        } else {
+            self.rows[0].process_with_scratch(&mut data[..len], &mut self.scratch);
+            self.transform_columns(data, false);
+        }
+    }
+
+    fn transform_columns(&mut self, data: &mut [Complex<f64>], inverse: bool) {
+        let (width, height) = (self.width, self.height);
+        // Columns are strided in memory, so they are copied out a few at a
+        // time to be transformed contiguously.
+        for start in (0..width).step_by(COLUMN_BATCH) {
+            let columns = (width - start).min(COLUMN_BATCH);
+            let batch = &mut self.batch[..columns * height];
+            for (y, row) in data.chunks_exact(width).enumerate() {
+                for (column, &value) in row[start..start + columns].iter().enumerate() {
+                    batch[column * height + y] = value;
+                }
+            }
+            self.columns[usize::from(inverse)].process_with_scratch(batch, &mut self.scratch);
+            for (y, row) in data.chunks_exact_mut(width).enumerate() {
+                for (column, out) in row[start..start + columns].iter_mut().enumerate() {
+                    *out = batch[column * height + y];
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmap::PixelView;
+    use crate::correlation::{Planes, ncc_scores};
+
+    fn planes<F: Fn(usize, usize) -> [u8; 3]>(width: usize, height: usize, pixel: F) -> Planes {
+        let mut data = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                data.extend(pixel(x, y));
+                data.push(255);
+            }
+        }
+        Planes::from_pixels(&PixelView::new(
+            &data,
+            width as u32,
+            height as u32,
+            width * 4,
+        ))
+    }
+
+    fn noise(width: usize, height: usize) -> Planes {
+        planes(width, height, |x, y| {
+            let v = ((x * 7919 + y * 104_729) ^ (x * y * 31)) as u32;
+            let v = v.wrapping_mul(2_654_435_761);
+            [(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8]
+        })
+    }
+
+    fn scores(haystack: &Planes, needle: &Planes) -> Vec<f64> {
+        let out_width = haystack.width - needle.width + 1;
+        let out_height = haystack.height - needle.height + 1;
+        let mut scores = vec![-1.0; out_width * out_height];
+        ncc_scores(haystack, needle, |x, y, score| {
+            assert_eq!(scores[y * out_width + x], -1.0);
+            scores[y * out_width + x] = score;
+        });
+        scores
+    }
+
+    #[test]
+    fn test_ncc_scores_peak_at_offset() {
+        let haystack = noise(24, 18);
+        let needle = planes(6, 5, |x, y| {
+            let idx = (y + 9) * haystack.width + x + 11;
+            [
+                haystack.channels[0][idx],
+                haystack.channels[1][idx],
+                haystack.channels[2][idx],
+            ]
+        });
+        let scores = scores(&haystack, &needle);
+        let out_width = haystack.width - needle.width + 1;
+        let (best, score) =
+            scores.iter().enumerate().fold(
+                (0, 0.0),
+                |acc, (i, &s)| if s > acc.1 { (i, s) } else { acc },
+            );
+        assert_eq!((best % out_width, best / out_width), (11, 9));
+        assert!((score - 1.0).abs() < 1e-9);
+        assert!(scores.iter().all(|s| (0.0..=1.0).contains(s)));
+    }
+
+    #[test]
+    fn test_ncc_scores_brightness_invariant() {
+        let haystack = noise(16, 16);
+        let needle = planes(5, 5, |x, y| {
+            let idx = (y + 3) * haystack.width + x + 4;
+            [
+                haystack.channels[0][idx] / 2 + 20,
+                haystack.channels[1][idx] / 2 + 20,
+                haystack.channels[2][idx] / 2 + 20,
+            ]
+        });
+        let scores = scores(&haystack, &needle);
+        assert!(scores[3 * 12 + 4] > 0.99);
+    }
+
+    #[test]
+    fn test_ncc_scores_across_tiles() {
+        // Large enough to be split into several overlapping tiles.
+        let haystack = noise(1300, 40);
+        let needle = planes(7, 6, |x, y| {
+            let idx = (y + 30) * haystack.width + x + 1100;
+            [
+                haystack.channels[0][idx],
+                haystack.channels[1][idx],
+                haystack.channels[2][idx],
+            ]
+        });
+        let scores = scores(&haystack, &needle);
+        let out_width = haystack.width - needle.width + 1;
+        assert!(scores.iter().all(|s| (0.0..=1.0).contains(s)));
+        assert_eq!(scores[30 * out_width + 1100], 1.0);
+        assert!(scores.iter().filter(|&&s| s == 1.0).count() == 1);
+    }
+}
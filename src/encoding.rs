@@ -0,0 +1,183 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! String encoding of bitmaps compatible with AutoPy.
+//!
+//! Bitmaps are encoded as `b<width>,<height>,<data>`, where `data` is the
+//! base64 encoding of the zlib-compressed pixels. Pixels are stored as 24-bit
+//! BGR values, with each row padded to a multiple of four bytes. Bitmaps with
+//! a scale other than 1 have it appended as `,<scale>`, which AutoPy does not
+//! understand.
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use image::{Rgba, RgbaImage};
+use std::io::{Read, Write};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const BYTES_PER_PIXEL: usize = 3;
+
+/// Returns the length of each encoded row, including padding.
+#[inline]
+fn row_length(width: usize) -> usize {
+    (width * BYTES_PER_PIXEL).div_ceil(4) * 4
+}
+
+/// Encodes an image with the given scale. Alpha is discarded.
+pub fn encode(image: &RgbaImage, scale: f64) -> String {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut pixels = vec![0u8; row_length(width) * height];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let offset = y as usize * row_length(width) + x as usize * BYTES_PER_PIXEL;
+        pixels[offset..offset + BYTES_PER_PIXEL].copy_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder
+        .write_all(&pixels)
+        .expect("writing to a Vec cannot fail");
+    let compressed = encoder.finish().expect("writing to a Vec cannot fail");
+
+    let mut encoded = format!("b{},{},{}", width, height, base64_encode(&compressed));
+    if scale != 1.0 {
+        encoded.push_str(&format!(",{}", scale));
+    }
+    encoded
+}
+
+/// Decodes a string created by `encode`, returning the image and its scale.
+pub fn decode(encoded: &str) -> Result<(RgbaImage, f64), &'static str> {
+    let mut fields = encoded
+        .trim()
+        .strip_prefix('b')
+        .ok_or("Encoded bitmap must start with 'b'.")?
+        .split(',');
+    let mut dimension = || {
+        fields
+            .next()
+            .and_then(|field| field.parse::<u32>().ok())
+            .ok_or("Encoded bitmap has an invalid size.")
+    };
+    let (width, height) = (dimension()?, dimension()?);
+    let data = fields.next().ok_or("Encoded bitmap has no pixel data.")?;
+    let scale = match fields.next() {
+        Some(field) => field
+            .parse::<f64>()
+            .ok()
+            .filter(|scale| scale.is_finite() && *scale > 0.0)
+            .ok_or("Encoded bitmap has an invalid scale.")?,
+        None => 1.0,
+    };
+    if fields.next().is_some() {
+        return Err("Encoded bitmap has unexpected trailing fields.");
+    }
+
+    let compressed = base64_decode(data).ok_or("Encoded bitmap has invalid base64 data.")?;
+    let mut pixels = Vec::new();
+    ZlibDecoder::new(compressed.as_slice())
+        .read_to_end(&mut pixels)
+        .map_err(|_| "Encoded bitmap has invalid compressed data.")?;
+
+    // Also accept rows without padding.
+    let (width_px, height_px) = (width as usize, height as usize);
+    let stride = if pixels.len() == row_length(width_px) * height_px {
+        row_length(width_px)
+    } else if pixels.len() == width_px * BYTES_PER_PIXEL * height_px {
+        width_px * BYTES_PER_PIXEL
+    } else {
+        return Err("Encoded bitmap data does not match its size.");
+    };
+    let image = RgbaImage::from_fn(width, height, |x, y| {
+        let offset = y as usize * stride + x as usize * BYTES_PER_PIXEL;
+        Rgba([pixels[offset + 2], pixels[offset + 1], pixels[offset], 255])
+    });
+    Ok((image, scale))
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = (u32::from(chunk[0]) << 16)
+            | (u32::from(*chunk.get(1).unwrap_or(&0)) << 8)
+            | u32::from(*chunk.get(2).unwrap_or(&0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = encoded
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    let unpadded = digits
+        .iter()
+        .rposition(|&byte| byte != b'=')
+        .map_or(0, |idx| idx + 1);
+    if digits.len() - unpadded > 2 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(unpadded * 3 / 4);
+    for chunk in digits[..unpadded].chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut group = 0u32;
+        for (i, digit) in chunk.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|c| c == digit)? as u32;
+            group |= value << (18 - 6 * i);
+        }
+        bytes.extend_from_slice(&group.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{base64_decode, base64_encode, decode, encode};
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_base64() {
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xff\xfe\x00\x10", "//4AEA=="),
+        ] {
+            assert_eq!(base64_encode(bytes), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), bytes);
+        }
+        assert_eq!(base64_decode("Zg"), Some(b"f".to_vec()));
+        assert_eq!(base64_decode("Z"), None);
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let image = RgbaImage::from_fn(5, 3, |x, y| Rgba([x as u8 * 40, y as u8 * 90, 7, 255]));
+        let encoded = encode(&image, 1.0);
+        assert!(encoded.starts_with("b5,3,"));
+        assert_eq!(decode(&encoded), Ok((image.clone(), 1.0)));
+        assert_eq!(decode(&encode(&image, 2.0)), Ok((image, 2.0)));
+
+        assert!(decode("5,3,eJw=").is_err());
+        assert!(decode("b5,3").is_err());
+        assert!(decode("b5,3,eJxjYAAAAAQAAQ==").is_err());
+    }
+}
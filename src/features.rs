@@ -0,0 +1,576 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! This module defines types for locating bitmaps by their features rather
+//! than by comparing pixels, via `Bitmap::find_features`.
+//!
+//! Corners are detected with FAST at several scales, described with
+//! rotation-aware binary descriptors in the style of ORB, and matched between
+//! the needle and haystack. A homography is then fitted to the matches with
+//! RANSAC, which finds needles that are scaled, slightly rotated or partly
+//! covered.
+use crate::geometry::Point;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Minimum brightness difference for a pixel on the FAST circle to count.
+const FAST_THRESHOLD: i16 = 20;
+
+/// Radius of the patch used to compute orientation and descriptors.
+const PATCH_RADIUS: i64 = 15;
+
+/// Number of binary tests in each descriptor.
+const DESCRIPTOR_BITS: usize = 256;
+
+/// Maximum Hamming distance for two descriptors to be considered a match.
+const MAX_DESCRIPTOR_DISTANCE: u32 = 64;
+
+/// Maximum ratio between the best and second best descriptor distances.
+const MATCH_RATIO: f64 = 0.8;
+
+/// Scale factor between pyramid levels.
+const PYRAMID_FACTOR: f64 = 1.25;
+
+const PYRAMID_LEVELS: usize = 8;
+
+const RANSAC_ITERATIONS: usize = 2000;
+
+const REFINE_ITERATIONS: usize = 5;
+
+/// Maximum reprojection error of an inlier, in haystack pixels.
+const RANSAC_THRESHOLD: f64 = 2.0;
+
+/// Circle of radius 3 sampled by FAST, in order.
+const FAST_CIRCLE: [(i64, i64); 16] = [
+    (0, -3),
+    (1, -3),
+    (2, -2),
+    (3, -1),
+    (3, 0),
+    (3, 1),
+    (2, 2),
+    (1, 3),
+    (0, 3),
+    (-1, 3),
+    (-2, 2),
+    (-3, 1),
+    (-3, 0),
+    (-3, -1),
+    (-2, -2),
+    (-1, -3),
+];
+
+/// A projective transform between two planes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Homography(pub [[f64; 3]; 3]);
+
+impl Homography {
+    /// Returns `point` mapped through the transform.
+    pub fn project(&self, point: Point) -> Point {
+        let m = &self.0;
+        let w = m[2][0] * point.x + m[2][1] * point.y + m[2][2];
+        Point::new(
+            (m[0][0] * point.x + m[0][1] * point.y + m[0][2]) / w,
+            (m[1][0] * point.x + m[1][1] * point.y + m[1][2]) / w,
+        )
+    }
+
+    fn scaled(&self, from: f64, to: f64) -> Homography {
+        let mut m = self.0;
+        for row in m.iter_mut().take(2) {
+            row[0] *= from / to;
+            row[1] *= from / to;
+            row[2] /= to;
+        }
+        m[2][0] *= from;
+        m[2][1] *= from;
+        Homography(m)
+    }
+
+    fn translated(&self, offset: Point) -> Homography {
+        let [row_x, row_y, row_w] = self.0;
+        let translate = |row: [f64; 3], delta: f64| [0, 1, 2].map(|i| row[i] + delta * row_w[i]);
+        Homography([
+            translate(row_x, offset.x),
+            translate(row_y, offset.y),
+            row_w,
+        ])
+    }
+}
+
+/// A needle located inside a bitmap by its features.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FeatureMatch {
+    /// Corners of the needle projected onto the haystack, in points,
+    /// starting at its top-left corner and going clockwise.
+    pub quad: [Point; 4],
+
+    /// Transform from points in the needle to points in the haystack.
+    pub homography: Homography,
+
+    /// Number of feature matches agreeing with the homography.
+    pub inliers: usize,
+}
+
+impl FeatureMatch {
+    /// Returns the center of the needle projected onto the haystack.
+    pub fn center(&self) -> Point {
+        let (x, y) = self
+            .quad
+            .iter()
+            .fold((0.0, 0.0), |(x, y), point| (x + point.x, y + point.y));
+        Point::new(x / 4.0, y / 4.0)
+    }
+}
+
+/// A point in the needle paired with a point in the haystack, in pixels.
+type Correspondence = ((f64, f64), (f64, f64));
+
+/// A detected corner with its descriptor. Coordinates are in level 0 pixels,
+/// measured from the top-left edge of the image rather than pixel centers.
+struct Feature {
+    x: f64,
+    y: f64,
+    descriptor: [u64; DESCRIPTOR_BITS / 64],
+}
+
+/// Locates `needle` in `haystack`, given their scales, returning `None` if
+/// fewer than `min_inliers` features agree on its position. Results are
+/// offset by `origin`, in points.
+pub(crate) fn locate(
+    haystack: &DynamicImage,
+    haystack_scale: f64,
+    origin: Point,
+    needle: &DynamicImage,
+    needle_scale: f64,
+    min_inliers: usize,
+) -> Option<FeatureMatch> {
+    let needle_features = detect(needle, 500);
+    let haystack_features = detect(haystack, 5000);
+    let pairs = match_features(&needle_features, &haystack_features);
+    if pairs.len() < min_inliers.max(4) {
+        return None;
+    }
+
+    let correspondences: Vec<Correspondence> = pairs
+        .iter()
+        .map(|&(n, h)| {
+            (
+                (needle_features[n].x, needle_features[n].y),
+                (haystack_features[h].x, haystack_features[h].y),
+            )
+        })
+        .collect();
+    let (homography, inliers) = ransac(&correspondences)?;
+    if inliers < min_inliers {
+        return None;
+    }
+
+    let (width, height) = (f64::from(needle.width()), f64::from(needle.height()));
+    let homography = homography
+        .scaled(needle_scale, haystack_scale)
+        .translated(origin);
+    let corners = [
+        Point::ZERO,
+        Point::new(width, 0.0),
+        Point::new(width, height),
+        Point::new(0.0, height),
+    ];
+    let quad = corners.map(|corner| homography.project(corner.scaled(1.0 / needle_scale)));
+    if !is_convex(&quad) {
+        return None;
+    }
+
+    Some(FeatureMatch {
+        quad,
+        homography,
+        inliers,
+    })
+}
+
+/// Detects up to `max_features` corners across a scale pyramid.
+fn detect(image: &DynamicImage, max_features: usize) -> Vec<Feature> {
+    let pattern = descriptor_pattern();
+    let mut level_image = image.to_luma8();
+    let mut level_scale = 1.0;
+    let mut levels: Vec<(GrayImage, f64)> = Vec::new();
+    let min_size = (PATCH_RADIUS as u32 + 4) * 2;
+    while levels.len() < PYRAMID_LEVELS
+        && level_image.width() >= min_size
+        && level_image.height() >= min_size
+    {
+        let next_width = (f64::from(level_image.width()) / PYRAMID_FACTOR).round() as u32;
+        let next_height = (f64::from(level_image.height()) / PYRAMID_FACTOR).round() as u32;
+        let next = imageops::resize(&level_image, next_width, next_height, FilterType::Triangle);
+        levels.push((level_image, level_scale));
+        level_image = next;
+        level_scale *= PYRAMID_FACTOR;
+    }
+
+    // Spread features across levels in proportion to their area.
+    let total_area: f64 = levels
+        .iter()
+        .map(|(level, _)| f64::from(level.width()) * f64::from(level.height()))
+        .sum();
+    let mut features = Vec::new();
+    for (level, scale) in &levels {
+        let area = f64::from(level.width()) * f64::from(level.height());
+        let budget = ((max_features as f64) * area / total_area).ceil() as usize;
+        let smoothed = imageops::blur(level, 2.0);
+        for (x, y) in fast_corners(level, budget) {
+            let angle = orientation(level, x, y);
+            features.push(Feature {
+                x: (x as f64 + 0.5) * scale,
+                y: (y as f64 + 0.5) * scale,
+                descriptor: describe(&smoothed, x, y, angle, &pattern),
+            });
+        }
+    }
+    features
+}
+
+/// Returns the strongest FAST-9 corners, after non-maximum suppression,
+/// that are far enough from the edge to be described.
+fn fast_corners(image: &GrayImage, limit: usize) -> Vec<(i64, i64)> {
+    let (width, height) = (i64::from(image.width()), i64::from(image.height()));
+    let border = PATCH_RADIUS + 1;
+    if width <= border * 2 || height <= border * 2 {
+        return Vec::new();
+    }
+
+    let pixel = |x: i64, y: i64| i16::from(image.get_pixel(x as u32, y as u32)[0]);
+    let mut scores = vec![0u32; (width * height) as usize];
+    for y in border..height - border {
+        for x in border..width - border {
+            let center = pixel(x, y);
+            let ring: Vec<i16> = FAST_CIRCLE
+                .iter()
+                .map(|(dx, dy)| pixel(x + dx, y + dy) - center)
+                .collect();
+            let score = [1i16, -1]
+                .iter()
+                .filter(|&&sign| {
+                    let mut run = 0;
+                    (0..ring.len() + 8).any(|i| {
+                        run = if ring[i % ring.len()] * sign > FAST_THRESHOLD {
+                            run + 1
+                        } else {
+                            0
+                        };
+                        run >= 9
+                    })
+                })
+                .map(|&sign| {
+                    ring.iter()
+                        .map(|d| (d * sign - FAST_THRESHOLD).max(0) as u32)
+                        .sum::<u32>()
+                })
+                .max()
+                .unwrap_or(0);
+            scores[(y * width + x) as usize] = score;
+        }
+    }
+
+    let mut corners: Vec<(u32, i64, i64)> = Vec::new();
+    for y in border..height - border {
+        for x in border..width - border {
+            let score = scores[(y * width + x) as usize];
+            let is_max = score > 0
+                && (-1..=1).all(|dy| {
+                    (-1..=1).all(|dx| {
+                        let other = scores[((y + dy) * width + x + dx) as usize];
+                        (dx == 0 && dy == 0)
+                            || other < score
+                            || (other == score && (dy, dx) > (0, 0))
+                    })
+                });
+            if is_max {
+                corners.push((score, x, y));
+            }
+        }
+    }
+    corners.sort_by_key(|&(score, _, _)| std::cmp::Reverse(score));
+    corners.truncate(limit);
+    corners.into_iter().map(|(_, x, y)| (x, y)).collect()
+}
+
+/// Returns the angle from a corner to the intensity centroid of its patch.
+fn orientation(image: &GrayImage, x: i64, y: i64) -> f64 {
+    let (mut m01, mut m10) = (0.0, 0.0);
+    for dy in -PATCH_RADIUS..=PATCH_RADIUS {
+        for dx in -PATCH_RADIUS..=PATCH_RADIUS {
+            if dx * dx + dy * dy <= PATCH_RADIUS * PATCH_RADIUS {
+                let value = f64::from(image.get_pixel((x + dx) as u32, (y + dy) as u32)[0]);
+                m10 += dx as f64 * value;
+                m01 += dy as f64 * value;
+            }
+        }
+    }
+    m01.atan2(m10)
+}
+
+/// Returns the fixed set of point pairs compared by each descriptor,
+/// sampled from a Gaussian distribution inside the patch.
+fn descriptor_pattern() -> Vec<[(f64, f64); 2]> {
+    let mut rng = StdRng::seed_from_u64(0x004f_5242);
+    let limit = (PATCH_RADIUS - 2) as f64;
+    let mut sample = || loop {
+        // Box-Muller transform, with a standard deviation of a fifth of the
+        // patch size as in BRIEF.
+        let (u1, u2): (f64, f64) = (rng.random_range(1e-9..1.0), rng.random_range(0.0..1.0));
+        let radius = (-2.0 * u1.ln()).sqrt() * (PATCH_RADIUS * 2 + 1) as f64 / 5.0;
+        let (x, y) = (
+            radius * (std::f64::consts::TAU * u2).cos(),
+            radius * (std::f64::consts::TAU * u2).sin(),
+        );
+        if x.hypot(y) <= limit {
+            return (x, y);
+        }
+    };
+    (0..DESCRIPTOR_BITS).map(|_| [sample(), sample()]).collect()
+}
+
+/// Returns the binary descriptor of a corner, with the pattern rotated by
+/// `angle` so that descriptors are invariant to rotation.
+fn describe(
+    smoothed: &GrayImage,
+    x: i64,
+    y: i64,
+    angle: f64,
+    pattern: &[[(f64, f64); 2]],
+) -> [u64; DESCRIPTOR_BITS / 64] {
+    let (sin, cos) = angle.sin_cos();
+    let sample = |(px, py): (f64, f64)| {
+        let rx = (px * cos - py * sin).round() as i64;
+        let ry = (px * sin + py * cos).round() as i64;
+        smoothed.get_pixel((x + rx) as u32, (y + ry) as u32)[0]
+    };
+    let mut descriptor = [0u64; DESCRIPTOR_BITS / 64];
+    for (bit, [a, b]) in pattern.iter().enumerate() {
+        if sample(*a) < sample(*b) {
+            descriptor[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+    descriptor
+}
+
+fn hamming(a: &[u64; DESCRIPTOR_BITS / 64], b: &[u64; DESCRIPTOR_BITS / 64]) -> u32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum()
+}
+
+/// Returns pairs of needle and haystack feature indices whose descriptors
+/// match distinctly.
+fn match_features(needle: &[Feature], haystack: &[Feature]) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for (n, feature) in needle.iter().enumerate() {
+        let mut best: Option<(usize, u32)> = None;
+        let mut second = u32::MAX;
+        for (h, other) in haystack.iter().enumerate() {
+            let distance = hamming(&feature.descriptor, &other.descriptor);
+            match best {
+                Some((_, best_distance)) if distance >= best_distance => {
+                    second = second.min(distance);
+                }
+                _ => {
+                    second = best.map_or(second, |(_, d)| d);
+                    best = Some((h, distance));
+                }
+            }
+        }
+        if let Some((h, distance)) = best
+            && distance <= MAX_DESCRIPTOR_DISTANCE
+            && f64::from(distance) < MATCH_RATIO * f64::from(second)
+        {
+            pairs.push((n, h));
+        }
+    }
+    pairs
+}
+
+/// Fits a homography to the given correspondences with RANSAC, returning
+/// it along with its number of inliers.
+fn ransac(correspondences: &[Correspondence]) -> Option<(Homography, usize)> {
+    let mut rng = StdRng::seed_from_u64(0x5241_4e53);
+    let inliers_of = |homography: &Homography| -> Vec<usize> {
+        (0..correspondences.len())
+            .filter(|&i| {
+                let ((x, y), (u, v)) = correspondences[i];
+                let projected = homography.project(Point::new(x, y));
+                (projected.x - u).hypot(projected.y - v) <= RANSAC_THRESHOLD
+            })
+            .collect()
+    };
+
+    let mut best: Option<(Homography, Vec<usize>)> = None;
+    for _ in 0..RANSAC_ITERATIONS {
+        let sample = rand::seq::index::sample(&mut rng, correspondences.len(), 4);
+        let points: Vec<_> = sample.iter().map(|i| correspondences[i]).collect();
+        if let Some(homography) = fit_homography(&points) {
+            let inliers = inliers_of(&homography);
+            if best
+                .as_ref()
+                .is_none_or(|(_, best)| inliers.len() > best.len())
+            {
+                best = Some((homography, inliers));
+            }
+        }
+    }
+
+    // Refine using every inlier of the best model, for as long as that does
+    // not lose any.
+    let (mut homography, mut inliers) = best?;
+    for _ in 0..REFINE_ITERATIONS {
+        let points: Vec<_> = inliers.iter().map(|&i| correspondences[i]).collect();
+        match fit_homography(&points) {
+            Some(refined) if inliers_of(&refined).len() >= inliers.len() => {
+                inliers = inliers_of(&refined);
+                homography = refined;
+            }
+            _ => break,
+        }
+    }
+    Some((homography, inliers.len()))
+}
+
+/// Fits a homography to four or more correspondences by least squares,
+/// after normalizing both point sets for numerical stability.
+fn fit_homography(correspondences: &[Correspondence]) -> Option<Homography> {
+    let normalization = |points: Vec<(f64, f64)>| {
+        let n = points.len() as f64;
+        let (cx, cy) = points
+            .iter()
+            .fold((0.0, 0.0), |(x, y), p| (x + p.0 / n, y + p.1 / n));
+        let spread = points
+            .iter()
+            .map(|p| (p.0 - cx).hypot(p.1 - cy))
+            .sum::<f64>()
+            / n;
+        let s = if spread > 0.0 {
+            std::f64::consts::SQRT_2 / spread
+        } else {
+            1.0
+        };
+        [[s, 0.0, -s * cx], [0.0, s, -s * cy], [0.0, 0.0, 1.0]]
+    };
+    let source = normalization(correspondences.iter().map(|c| c.0).collect());
+    let target = normalization(correspondences.iter().map(|c| c.1).collect());
+    let apply =
+        |m: &[[f64; 3]; 3], (x, y): (f64, f64)| (m[0][0] * x + m[0][2], m[1][1] * y + m[1][2]);
+
+    // Normal equations of the direct linear transform with h33 = 1.
+    let mut ata = [[0.0; 8]; 8];
+    let mut atb = [0.0; 8];
+    for &(from, to) in correspondences {
+        let (x, y) = apply(&source, from);
+        let (u, v) = apply(&target, to);
+        let rows = [
+            ([x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y], u),
+            ([0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y], v),
+        ];
+        for (row, rhs) in rows {
+            for i in 0..8 {
+                atb[i] += row[i] * rhs;
+                for j in 0..8 {
+                    ata[i][j] += row[i] * row[j];
+                }
+            }
+        }
+    }
+    let h = solve(ata, atb)?;
+    let normalized = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], 1.0]];
+
+    // Undo the normalization: H = T_target^-1 * H_normalized * T_source.
+    let inverse_target = [
+        [1.0 / target[0][0], 0.0, -target[0][2] / target[0][0]],
+        [0.0, 1.0 / target[1][1], -target[1][2] / target[1][1]],
+        [0.0, 0.0, 1.0],
+    ];
+    let m = multiply(&multiply(&inverse_target, &normalized), &source);
+    if m[2][2].abs() < 1e-12 || m.iter().flatten().any(|v| !v.is_finite()) {
+        return None;
+    }
+    Some(Homography(m.map(|row| row.map(|v| v / m[2][2]))))
+}
+
+fn multiply(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+/// Solves a linear system by Gaussian elimination with partial pivoting.
+fn solve(mut a: [[f64; 8]; 8], mut b: [f64; 8]) -> Option<[f64; 8]> {
+    for column in 0..8 {
+        let pivot =
+            (column..8).max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))?;
+        if a[pivot][column].abs() < 1e-10 {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        let (pivot_row, pivot_b) = (a[column], b[column]);
+        for row in column + 1..8 {
+            let factor = a[row][column] / pivot_row[column];
+            for (value, pivot_value) in a[row].iter_mut().zip(pivot_row.iter()).skip(column) {
+                *value -= factor * pivot_value;
+            }
+            b[row] -= factor * pivot_b;
+        }
+    }
+    let mut x = [0.0; 8];
+    for row in (0..8).rev() {
+        let sum: f64 = (row + 1..8).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Returns true if the quad is convex and wound clockwise on screen, i.e.
+/// the needle was not flipped or collapsed.
+fn is_convex(quad: &[Point; 4]) -> bool {
+    (0..4).all(|i| {
+        let (a, b, c) = (quad[i], quad[(i + 1) % 4], quad[(i + 2) % 4]);
+        (b.x - a.x) * (c.y - b.y) - (b.y - a.y) * (c.x - b.x) > 0.0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::features::{Homography, fit_homography};
+    use crate::geometry::Point;
+
+    #[test]
+    fn test_fit_homography() {
+        let expected = Homography([[1.2, 0.1, 30.0], [-0.05, 0.9, 12.0], [0.0001, 0.0002, 1.0]]);
+        let correspondences: Vec<_> = [
+            (0.0, 0.0),
+            (100.0, 0.0),
+            (100.0, 80.0),
+            (0.0, 80.0),
+            (40.0, 30.0),
+        ]
+        .iter()
+        .map(|&(x, y)| {
+            let projected = expected.project(Point::new(x, y));
+            ((x, y), (projected.x, projected.y))
+        })
+        .collect();
+        let fitted = fit_homography(&correspondences).unwrap();
+        for (a, b) in fitted.0.iter().flatten().zip(expected.0.iter().flatten()) {
+            assert!((a - b).abs() < 1e-6, "{:?} != {:?}", fitted, expected);
+        }
+    }
+}
@@ -142,6 +142,31 @@ impl Rect {
         self.origin.y + self.size.height
     }
 
+    /// Returns the overlapping area of the two rects, or `None` if they do
+    /// not overlap.
+    pub fn intersection(&self, rect: Rect) -> Option<Rect> {
+        let min_x = self.origin.x.max(rect.origin.x);
+        let min_y = self.origin.y.max(rect.origin.y);
+        let max_x = self.max_x().min(rect.max_x());
+        let max_y = self.max_y().min(rect.max_y());
+        if min_x < max_x && min_y < max_y {
+            Some(Rect::new(
+                Point::new(min_x, min_y),
+                Size::new(max_x - min_x, max_y - min_y),
+            ))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn center(&self) -> Point {
+        Point::new(
+            self.origin.x + self.size.width / 2.0,
+            self.origin.y + self.size.height / 2.0,
+        )
+    }
+
     pub fn iter_point(&self, point: Point) -> Option<Point> {
         if point.y + 1.0 < self.max_y() {
             Some(Point::new(point.x, point.y + 1.0))
@@ -0,0 +1,292 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! This module contains functions for comparing bitmaps to golden images
+//! stored as PNGs, for use in visual regression tests.
+//!
+//! When a bitmap does not match its golden image, the actual and expected
+//! bitmaps and a diff highlighting the changed pixels are written next to the
+//! golden image, as `<name>.actual.png`, `<name>.expected.png` and
+//! `<name>.diff.png`.
+//!
+//! Setting the `AUTOPILOT_UPDATE_GOLDENS` environment variable to anything
+//! other than an empty string or `0` overwrites golden images with the bitmaps
+//! compared to them instead, which is how new golden images are created.
+use crate::bitmap::Bitmap;
+use crate::geometry::Rect;
+use image::{DynamicImage, ImageError, Rgba};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Environment variable that switches comparisons to updating golden images.
+pub const UPDATE_VAR: &str = "AUTOPILOT_UPDATE_GOLDENS";
+
+/// Details of a bitmap that did not match its golden image.
+#[derive(Clone, Debug)]
+pub struct GoldenMismatch {
+    /// Path of the golden image.
+    pub golden: PathBuf,
+
+    /// Percentage of pixels that changed, from 0 to 100.
+    pub percent_changed: f64,
+
+    /// Bounds of each changed area, in points.
+    pub regions: Vec<Rect>,
+
+    /// Path the bitmap being compared was written to.
+    pub actual: PathBuf,
+
+    /// Path the golden image was copied to.
+    pub expected: PathBuf,
+
+    /// Path the diff highlighting changed pixels was written to.
+    pub diff: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    /// The golden image does not exist.
+    Missing(PathBuf),
+
+    /// The bitmap and golden image have different scales.
+    ScaleMismatch { expected: f64, actual: f64 },
+
+    /// The bitmap does not match the golden image.
+    Mismatch(Box<GoldenMismatch>),
+
+    /// The golden image or an artifact could not be read or written.
+    Image(ImageError),
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GoldenError::Missing(path) => write!(
+                f,
+                "Golden image {} does not exist; set {}=1 to create it",
+                path.display(),
+                UPDATE_VAR
+            ),
+            GoldenError::ScaleMismatch { expected, actual } => write!(
+                f,
+                "Bitmap has a scale of {} but its golden image has a scale of {}",
+                actual, expected
+            ),
+            GoldenError::Mismatch(mismatch) => write!(
+                f,
+                "Bitmap does not match golden image {}: {:.2}% of pixels changed in {} \
+                 region(s). Wrote {}, {} and {}; set {}=1 to update the golden image",
+                mismatch.golden.display(),
+                mismatch.percent_changed,
+                mismatch.regions.len(),
+                mismatch.actual.display(),
+                mismatch.expected.display(),
+                mismatch.diff.display(),
+                UPDATE_VAR
+            ),
+            GoldenError::Image(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GoldenError::Image(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ImageError> for GoldenError {
+    fn from(err: ImageError) -> GoldenError {
+        GoldenError::Image(err)
+    }
+}
+
+impl From<std::io::Error> for GoldenError {
+    fn from(err: std::io::Error) -> GoldenError {
+        GoldenError::Image(ImageError::IoError(err))
+    }
+}
+
+/// Compares `bitmap` to the golden image at `path`.
+///
+/// Pixels are considered changed if their colors do not match within
+/// `tolerance`, as accepted by `Bitmap::find_color`. Pixels within any of the
+/// `ignore` rects, given in points, are not compared, which is useful for
+/// areas such as clocks or cursors that change between runs.
+///
+/// Returns `GoldenError::Mismatch` after writing the actual, expected and
+/// diff images if any pixels changed. If `AUTOPILOT_UPDATE_GOLDENS` is set,
+/// the golden image is overwritten with `bitmap` instead.
+pub fn compare_to_golden<P: AsRef<Path>>(
+    bitmap: &Bitmap,
+    path: P,
+    tolerance: Option<f64>,
+    ignore: &[Rect],
+) -> Result<(), GoldenError> {
+    let update = std::env::var(UPDATE_VAR).is_ok_and(|value| !value.is_empty() && value != "0");
+    check_golden(bitmap, path.as_ref(), tolerance, ignore, update)
+}
+
+/// Asserts that `bitmap` matches the golden image at `path`, as described in
+/// `compare_to_golden`.
+///
+/// # Panics
+///
+/// Panics with a description of the differences if it does not match.
+#[track_caller]
+pub fn assert_golden<P: AsRef<Path>>(
+    bitmap: &Bitmap,
+    path: P,
+    tolerance: Option<f64>,
+    ignore: &[Rect],
+) {
+    if let Err(err) = compare_to_golden(bitmap, path, tolerance, ignore) {
+        panic!("{}", err);
+    }
+}
+
+fn check_golden(
+    bitmap: &Bitmap,
+    path: &Path,
+    tolerance: Option<f64>,
+    ignore: &[Rect],
+    update: bool,
+) -> Result<(), GoldenError> {
+    let artifacts = ["actual", "expected", "diff"].map(|kind| artifact_path(path, kind));
+    if update {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        bitmap.save(path)?;
+        remove_artifacts(&artifacts)?;
+        return Ok(());
+    }
+    if !path.is_file() {
+        return Err(GoldenError::Missing(path.to_path_buf()));
+    }
+
+    let expected = Bitmap::open(path)?;
+    if expected.scale != bitmap.scale {
+        return Err(GoldenError::ScaleMismatch {
+            expected: expected.scale,
+            actual: bitmap.scale,
+        });
+    }
+
+    let diff = masked(&expected, ignore).diff(&masked(bitmap, ignore), tolerance);
+    if diff.is_empty() {
+        remove_artifacts(&artifacts)?;
+        return Ok(());
+    }
+
+    let [actual_path, expected_path, diff_path] = artifacts;
+    bitmap.save(&actual_path)?;
+    expected.save(&expected_path)?;
+    diff.image.save(&diff_path)?;
+    Err(GoldenError::Mismatch(Box::new(GoldenMismatch {
+        golden: path.to_path_buf(),
+        percent_changed: diff.percent_changed,
+        regions: diff.regions,
+        actual: actual_path,
+        expected: expected_path,
+        diff: diff_path,
+    })))
+}
+
+/// Returns the path of an artifact written next to the golden image, e.g.
+/// `button.diff.png` for `button.png`.
+fn artifact_path(path: &Path, kind: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}.png", stem, kind))
+}
+
+/// Removes artifacts left over from a previous failed comparison.
+fn remove_artifacts(paths: &[PathBuf]) -> std::io::Result<()> {
+    for path in paths {
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns a copy of the bitmap with the given rects, in points, cleared to
+/// transparent black so that they always compare equal.
+fn masked(bitmap: &Bitmap, ignore: &[Rect]) -> Bitmap {
+    if ignore.is_empty() {
+        return bitmap.clone();
+    }
+    let mut image = bitmap.image.to_rgba8();
+    for rect in ignore {
+        let rect = rect.scaled(bitmap.scale).round();
+        let min_x = rect.origin.x.max(0.0) as u32;
+        let min_y = rect.origin.y.max(0.0) as u32;
+        let max_x = (rect.max_x().max(0.0) as u32).min(image.width());
+        let max_y = (rect.max_y().max(0.0) as u32).min(image.height());
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                image.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            }
+        }
+    }
+    Bitmap::new(DynamicImage::ImageRgba8(image), Some(bitmap.scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmap::Bitmap;
+    use crate::geometry::{Point, Rect, Size};
+    use crate::golden::{artifact_path, check_golden, GoldenError};
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    #[test]
+    fn test_check_golden() {
+        let dir = std::env::temp_dir().join(format!("autopilot-golden-{}", std::process::id()));
+        let path = dir.join("gradient.png");
+        let image = RgbaImage::from_fn(20, 10, |x, y| Rgba([x as u8 * 10, y as u8 * 20, 0, 255]));
+        let bitmap = Bitmap::new(DynamicImage::ImageRgba8(image.clone()), None);
+
+        assert!(matches!(
+            check_golden(&bitmap, &path, None, &[], false),
+            Err(GoldenError::Missing(_))
+        ));
+        check_golden(&bitmap, &path, None, &[], true).unwrap();
+        check_golden(&bitmap, &path, None, &[], false).unwrap();
+
+        let mut changed = image;
+        changed.put_pixel(15, 5, Rgba([255, 255, 255, 255]));
+        let changed = Bitmap::new(DynamicImage::ImageRgba8(changed), None);
+        match check_golden(&changed, &path, None, &[], false) {
+            Err(GoldenError::Mismatch(mismatch)) => {
+                assert_eq!(mismatch.percent_changed, 0.5);
+                assert_eq!(
+                    mismatch.regions,
+                    vec![Rect::new(Point::new(15.0, 5.0), Size::new(1.0, 1.0))]
+                );
+                assert_eq!(Bitmap::open(&mismatch.actual).unwrap(), changed);
+                assert_eq!(Bitmap::open(&mismatch.expected).unwrap(), bitmap);
+                assert!(mismatch.diff.is_file());
+            }
+            result => panic!("Expected a mismatch, got {:?}", result),
+        }
+
+        let ignore = [Rect::new(Point::new(14.0, 4.0), Size::new(3.0, 3.0))];
+        check_golden(&changed, &path, None, &ignore, false).unwrap();
+        assert!(!artifact_path(&path, "diff").exists());
+
+        let scaled = Bitmap::new(bitmap.image.clone(), Some(2.0));
+        assert!(matches!(
+            check_golden(&scaled, &path, None, &[], false),
+            Err(GoldenError::ScaleMismatch { .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -13,6 +13,10 @@ use core_graphics::base::CGFloat;
 use core_graphics::geometry::{CGPoint, CGRect, CGSize};
 #[cfg(target_os = "linux")]
 use std::ptr::NonNull;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+#[cfg(target_os = "linux")]
+use std::sync::Mutex;
 
 #[cfg(target_os = "macos")]
 impl From<Point> for CGPoint {
@@ -52,13 +56,39 @@ impl From<Rect> for CGRect {
     }
 }
 
+/// A connection to the X server, closed when the thread that opened it
+/// exits so that background threads don't leak client slots.
+#[cfg(target_os = "linux")]
+pub struct XDisplay(NonNull<x11::xlib::Display>);
+
+#[cfg(target_os = "linux")]
+impl std::ops::Deref for XDisplay {
+    type Target = NonNull<x11::xlib::Display>;
+
+    fn deref(&self) -> &NonNull<x11::xlib::Display> {
+        &self.0
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for XDisplay {
+    fn drop(&mut self) {
+        // Shared memory segments are attached to the connection, so they
+        // must be detached before it closes.
+        crate::shm::release();
+        unsafe {
+            x11::xlib::XCloseDisplay(self.0.as_ptr());
+        }
+    }
+}
+
 #[cfg(target_os = "linux")]
-thread_local!(pub static X_MAIN_DISPLAY: NonNull<x11::xlib::Display> = unsafe {
+thread_local!(pub static X_MAIN_DISPLAY: XDisplay = unsafe {
     let display = x11::xlib::XOpenDisplay(std::ptr::null());
     if display.is_null() {
         panic!("Can't open X display. Is it currently running?");
     }
-    NonNull::new_unchecked(display)
+    XDisplay(NonNull::new_unchecked(display))
 });
 
 #[cfg(target_os = "linux")]
@@ -111,3 +141,51 @@ thread_local!(pub static X_SCALE_FACTOR: f64 = {
         (scale * 100.0).floor() / 100.0
     })
 });
+
+/// Held while errors are being trapped, since the error handler is shared by
+/// every thread.
+#[cfg(target_os = "linux")]
+static X_ERROR_TRAP: Mutex<()> = Mutex::new(());
+
+/// The connection whose errors are being trapped.
+#[cfg(target_os = "linux")]
+static X_TRAPPED_DISPLAY: AtomicPtr<x11::xlib::Display> = AtomicPtr::new(std::ptr::null_mut());
+
+#[cfg(target_os = "linux")]
+static X_ERROR_OCCURRED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+unsafe extern "C" fn record_x_error(
+    display: *mut x11::xlib::Display,
+    _: *mut x11::xlib::XErrorEvent,
+) -> libc::c_int {
+    if display == X_TRAPPED_DISPLAY.load(Ordering::SeqCst) {
+        X_ERROR_OCCURRED.store(true, Ordering::SeqCst);
+    }
+    0
+}
+
+/// Runs `f` and waits for the server to process its requests, returning
+/// false if any of them failed. By default Xlib exits the process on errors,
+/// which happen e.g. when attaching a shared memory segment to a remote
+/// display or capturing a window that was just closed.
+///
+/// Only one thread traps errors at a time, so that the handler that was
+/// installed before is always the one restored.
+#[cfg(target_os = "linux")]
+pub fn trap_x_errors(display: *mut x11::xlib::Display, f: impl FnOnce()) -> bool {
+    let _lock = X_ERROR_TRAP.lock().unwrap_or_else(|err| err.into_inner());
+    unsafe {
+        x11::xlib::XSync(display, 0);
+        X_TRAPPED_DISPLAY.store(display, Ordering::SeqCst);
+        X_ERROR_OCCURRED.store(false, Ordering::SeqCst);
+        let previous = x11::xlib::XSetErrorHandler(Some(record_x_error));
+        defer!({
+            x11::xlib::XSync(display, 0);
+            x11::xlib::XSetErrorHandler(previous);
+            X_TRAPPED_DISPLAY.store(std::ptr::null_mut(), Ordering::SeqCst);
+        });
+        f();
+    }
+    !X_ERROR_OCCURRED.swap(false, Ordering::SeqCst)
+}
@@ -8,15 +8,32 @@
 //! autopilot is a simple, cross-platform GUI automation library for Rust.
 pub mod alert;
 pub mod bitmap;
+mod color;
+mod correlation;
+mod encoding;
+pub mod features;
+mod font;
 pub mod geometry;
+pub mod golden;
 mod internal;
 pub mod key;
 pub mod mouse;
+mod pyramid;
+pub mod recorder;
 pub mod screen;
+#[cfg(target_os = "linux")]
+mod shm;
+pub mod stream;
+mod summed_area;
 
+extern crate flate2;
 extern crate image;
 extern crate libc;
+extern crate png;
 extern crate rand;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+extern crate rustfft;
 
 #[cfg(test)]
 #[macro_use]
@@ -0,0 +1,523 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! Coarse-to-fine bitmap search using an image pyramid.
+//!
+//! Each level of the pyramid halves the resolution of the one below it,
+//! storing the summed RGB channels of each block of pixels. Candidates are
+//! first found by matching a downsampled needle against the coarsest level
+//! of the haystack, then confirmed against each finer level and finally
+//! pixel by pixel.
+//!
+//! An offset of the needle rarely lines up with the blocks of a level, so
+//! the needle is downsampled once for each phase, i.e. each position inside
+//! it of the first whole block of the haystack. Block sums of a match differ
+//! by at most the number of pixels in a block times the largest difference
+//! between the channel sums of two matching pixels, so this gives the same
+//! results as comparing every pixel directly.
+use crate::bitmap::{ColorMetric, MAX_TOLERANCE_DELTA};
+use crate::summed_area::SummedArea;
+use image::Rgba;
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// Maximum number of levels above the full resolution.
+const MAX_LEVELS: usize = 6;
+
+/// An RGBA image stored contiguously in row-major order.
+pub struct Grid<'a> {
+    pub width: usize,
+    pub height: usize,
+
+    /// RGBA bytes of each pixel.
+    pub data: Cow<'a, [u8]>,
+}
+
+impl Grid<'_> {
+    #[inline]
+    pub fn get(&self, x: usize, y: usize) -> Rgba<u8> {
+        let offset = (y * self.width + x) * 4;
+        let pixel = &self.data[offset..offset + 4];
+        Rgba([pixel[0], pixel[1], pixel[2], pixel[3]])
+    }
+
+    /// Returns the sum of the RGB channels of the pixel at `(x, y)`.
+    #[inline]
+    fn channel_sum(&self, x: usize, y: usize) -> u32 {
+        let offset = (y * self.width + x) * 4;
+        self.data[offset..offset + 3]
+            .iter()
+            .map(|&value| u32::from(value))
+            .sum()
+    }
+}
+
+/// Searches a haystack grid for a needle grid.
+pub struct TemplateSearch<'a> {
+    haystack: &'a Grid<'a>,
+    needle: &'a Grid<'a>,
+    tolerance: f64,
+    weights: Option<&'a [f64]>,
+    metric: ColorMetric,
+
+    /// Levels of the pyramid to compare, from coarsest to finest.
+    levels: Vec<Level>,
+}
+
+/// A level of the pyramid, made of blocks `cell` pixels wide and high.
+struct Level {
+    cell: usize,
+
+    /// Channel sums of each whole block of the haystack.
+    haystack: Blocks,
+
+    /// Channel sums of the blocks of the needle for each phase, indexed by
+    /// `py * cell + px`, where `(px, py)` is the position of the first whole
+    /// block of the haystack inside the needle.
+    phases: Vec<Blocks>,
+
+    /// Largest allowed difference between block sums.
+    max_delta: u32,
+}
+
+/// Channel sums of a grid of blocks, in row-major order.
+struct Blocks {
+    columns: usize,
+    rows: usize,
+    sums: Vec<u32>,
+}
+
+/// Offsets where the needle matches the coarsest level of a search, as
+/// returned by `TemplateSearch::candidates`.
+pub struct Candidates {
+    columns: Range<usize>,
+    rows: Range<usize>,
+
+    /// One bit for each offset in column-major order, or `None` if every
+    /// offset is a candidate.
+    mask: Option<Vec<u64>>,
+}
+
+impl<'a> TemplateSearch<'a> {
+    /// Prepares a search for `needle` in `haystack`, with a tolerance as
+    /// accepted by `colors_match`.
+    ///
+    /// If given, `weights` holds the weight of each needle pixel as accepted
+    /// by `weighted_colors_match`, in row-major order. Weighted searches
+    /// compare every pixel, since block sums no longer bound the weighted
+    /// distance.
+    pub fn new(
+        haystack: &'a Grid<'a>,
+        needle: &'a Grid<'a>,
+        tolerance: f64,
+        weights: Option<&'a [f64]>,
+    ) -> TemplateSearch<'a> {
+        TemplateSearch::with_metric(haystack, needle, tolerance, weights, ColorMetric::Rgb)
+    }
+
+    /// Prepares a search as with `new`, comparing pixels using `metric`.
+    /// Searches with metrics whose distance does not bound the difference
+    /// of channel sums compare every pixel.
+    pub fn with_metric(
+        haystack: &'a Grid<'a>,
+        needle: &'a Grid<'a>,
+        tolerance: f64,
+        weights: Option<&'a [f64]>,
+        metric: ColorMetric,
+    ) -> TemplateSearch<'a> {
+        if let Some(weights) = weights {
+            assert_eq!(weights.len(), needle.width * needle.height);
+        }
+        let bound = match weights {
+            Some(_) => None,
+            None => metric.channel_sum_bound(tolerance),
+        };
+        TemplateSearch {
+            haystack,
+            needle,
+            tolerance,
+            weights,
+            metric,
+            levels: bound.map_or_else(Vec::new, |bound| pyramid(haystack, needle, bound)),
+        }
+    }
+
+    /// Returns the offsets in `columns` and `rows` where the needle matches
+    /// the coarsest level of the pyramid, to be confirmed by `is_match`.
+    pub fn candidates(&self, columns: Range<usize>, rows: Range<usize>) -> Candidates {
+        // Offsets past these would place the needle outside the haystack.
+        let max_x = (self.haystack.width + 1).saturating_sub(self.needle.width);
+        let max_y = (self.haystack.height + 1).saturating_sub(self.needle.height);
+        let columns = columns.start.min(max_x)..columns.end.min(max_x);
+        let rows = rows.start.min(max_y)..rows.end.min(max_y);
+        let Some(level) = self.levels.first() else {
+            return Candidates {
+                columns,
+                rows,
+                mask: None,
+            };
+        };
+
+        // Match the needle downsampled at each phase against the haystack,
+        // which visits every offset exactly once.
+        let height = rows.len();
+        let mut mask = vec![0u64; (columns.len() * height).div_ceil(64)];
+        let cell = level.cell;
+        for py in 0..cell {
+            for px in 0..cell {
+                let phase = &level.phases[py * cell + px];
+                for block_y in (rows.start + py).div_ceil(cell).. {
+                    let y = block_y * cell - py;
+                    if y >= rows.end {
+                        break;
+                    }
+                    let haystack_row = &level.haystack.sums[block_y * level.haystack.columns..];
+                    let block_columns =
+                        (columns.start + px).div_ceil(cell)..(columns.end + px).div_ceil(cell);
+                    for block_x in block_columns {
+                        let x = block_x * cell - px;
+                        // Most offsets differ in their first block already.
+                        if haystack_row[block_x].abs_diff(phase.sums[0]) <= level.max_delta
+                            && level.phase_matches(phase, block_x, block_y)
+                        {
+                            let bit = (x - columns.start) * height + y - rows.start;
+                            mask[bit / 64] |= 1 << (bit % 64);
+                        }
+                    }
+                }
+            }
+        }
+        Candidates {
+            columns,
+            rows,
+            mask: Some(mask),
+        }
+    }
+
+    /// Returns true if the needle matches the haystack with its top-left
+    /// corner at `(x, y)`. The coarsest level is left to `candidates`.
+    pub fn is_match(&self, x: usize, y: usize) -> bool {
+        if self.needle.width == 0 || self.needle.height == 0 {
+            return true;
+        }
+        if x + self.needle.width > self.haystack.width
+            || y + self.needle.height > self.haystack.height
+        {
+            return false;
+        }
+
+        if !self.levels.iter().skip(1).all(|level| level.matches(x, y)) {
+            return false;
+        }
+
+        for needle_y in 0..self.needle.height {
+            for needle_x in 0..self.needle.width {
+                let c1 = self.needle.get(needle_x, needle_y);
+                let c2 = self.haystack.get(x + needle_x, y + needle_y);
+                let matches = match self.weights {
+                    Some(weights) => {
+                        let weight = weights[needle_y * self.needle.width + needle_x];
+                        self.metric.weighted_matches(c1, c2, weight, self.tolerance)
+                    }
+                    None => self.metric.matches(c1, c2, self.tolerance),
+                };
+                if !matches {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Returns the similarity of the needle to the haystack at `(x, y)`,
+    /// from 0 to 1, as one minus the mean (weighted) RGB distance between
+    /// their pixels.
+    pub fn score(&self, x: usize, y: usize) -> f64 {
+        let mut total_distance = 0.0;
+        let mut total_weight = 0.0;
+        for needle_y in 0..self.needle.height {
+            for needle_x in 0..self.needle.width {
+                let weight = self.weights.map_or(1.0, |weights| {
+                    weights[needle_y * self.needle.width + needle_x]
+                });
+                let c1 = self.needle.get(needle_x, needle_y);
+                let c2 = self.haystack.get(x + needle_x, y + needle_y);
+                let distance = (0..3)
+                    .map(|i| (f64::from(c1[i]) - f64::from(c2[i])).powi(2))
+                    .sum::<f64>()
+                    .sqrt();
+                total_distance += distance * weight;
+                total_weight += weight;
+            }
+        }
+        if total_weight == 0.0 {
+            1.0
+        } else {
+            1.0 - total_distance / total_weight / MAX_TOLERANCE_DELTA
+        }
+    }
+}
+
+impl Candidates {
+    /// Returns the candidate rows in the given range of column `x`.
+    pub fn in_column(&self, x: usize, rows: Range<usize>) -> impl Iterator<Item = usize> + '_ {
+        let rows = if self.columns.contains(&x) {
+            rows.start.max(self.rows.start)..rows.end.min(self.rows.end)
+        } else {
+            0..0
+        };
+        let column_start = (x.max(self.columns.start) - self.columns.start) * self.rows.len();
+        let (first_bit, end_bit) = (
+            column_start + rows.start.saturating_sub(self.rows.start),
+            column_start + rows.end.saturating_sub(self.rows.start),
+        );
+
+        // Skip over whole words without candidates rather than testing
+        // each bit.
+        let mut bit = first_bit;
+        let mut all_rows = rows.clone();
+        std::iter::from_fn(move || {
+            let Some(mask) = &self.mask else {
+                return all_rows.next();
+            };
+            while bit < end_bit {
+                let word = mask[bit / 64] >> (bit % 64);
+                if word == 0 {
+                    bit += 64 - bit % 64;
+                    continue;
+                }
+                bit += word.trailing_zeros() as usize;
+                if bit >= end_bit {
+                    break;
+                }
+                let row = bit - column_start + self.rows.start;
+                bit += 1;
+                return Some(row);
+            }
+            None
+        })
+    }
+}
+
+impl Level {
+    /// Returns true if the block sums of the needle at `(x, y)` are close
+    /// enough to those of the haystack.
+    fn matches(&self, x: usize, y: usize) -> bool {
+        let (block_x, block_y) = (x.div_ceil(self.cell), y.div_ceil(self.cell));
+        let (px, py) = (block_x * self.cell - x, block_y * self.cell - y);
+        self.phase_matches(&self.phases[py * self.cell + px], block_x, block_y)
+    }
+
+    /// Returns true if the blocks of `phase` are close enough to those of
+    /// the haystack starting at block `(block_x, block_y)`.
+    #[inline]
+    fn phase_matches(&self, phase: &Blocks, block_x: usize, block_y: usize) -> bool {
+        (0..phase.rows).all(|row| {
+            let start = (block_y + row) * self.haystack.columns + block_x;
+            let haystack = &self.haystack.sums[start..start + phase.columns];
+            let needle = &phase.sums[row * phase.columns..(row + 1) * phase.columns];
+            haystack
+                .iter()
+                .zip(needle)
+                .all(|(&a, &b)| a.abs_diff(b) <= self.max_delta)
+        })
+    }
+}
+
+impl Blocks {
+    /// Returns the sums of each 2x2 block of pixels of `grid`.
+    fn from_grid(grid: &Grid) -> Blocks {
+        let (columns, rows) = (grid.width / 2, grid.height / 2);
+        let mut sums = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            let (y, below) = (row * 2, row * 2 + 1);
+            sums.extend((0..columns).map(|column| {
+                let (x, right) = (column * 2, column * 2 + 1);
+                grid.channel_sum(x, y)
+                    + grid.channel_sum(right, y)
+                    + grid.channel_sum(x, below)
+                    + grid.channel_sum(right, below)
+            }));
+        }
+        Blocks {
+            columns,
+            rows,
+            sums,
+        }
+    }
+
+    /// Returns the sums of each 2x2 block of these blocks.
+    fn downsampled(&self) -> Blocks {
+        let (columns, rows) = (self.columns / 2, self.rows / 2);
+        let mut sums = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            let above = &self.sums[row * 2 * self.columns..][..self.columns];
+            let below = &self.sums[(row * 2 + 1) * self.columns..][..self.columns];
+            sums.extend((0..columns).map(|column| {
+                let x = column * 2;
+                above[x] + above[x + 1] + below[x] + below[x + 1]
+            }));
+        }
+        Blocks {
+            columns,
+            rows,
+            sums,
+        }
+    }
+
+    /// Returns the sums of the whole blocks of a grid of the given size,
+    /// starting at `(px, py)`.
+    fn from_summed_area(
+        table: &SummedArea,
+        width: usize,
+        height: usize,
+        cell: usize,
+        (px, py): (usize, usize),
+    ) -> Blocks {
+        let (columns, rows) = ((width - px) / cell, (height - py) / cell);
+        let mut sums = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            sums.extend((0..columns).map(|column| {
+                table.window(px + column * cell, py + row * cell, cell, cell) as u32
+            }));
+        }
+        Blocks {
+            columns,
+            rows,
+            sums,
+        }
+    }
+
+    /// Returns the standard deviation of the block sums.
+    fn spread(&self) -> f64 {
+        let count = self.sums.len() as f64;
+        let mean = self.sums.iter().map(|&sum| f64::from(sum)).sum::<f64>() / count;
+        let variance = self
+            .sums
+            .iter()
+            .map(|&sum| (f64::from(sum) - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        variance.sqrt()
+    }
+}
+
+/// Builds the levels of the pyramid worth comparing for a search whose
+/// matching pixels differ in their channel sums by at most `bound`.
+///
+/// Levels where the allowed difference is no smaller than the spread of
+/// the needle's own block sums would let through nearly every offset while
+/// costing more to compare than the pixels, so they are left out.
+fn pyramid(haystack: &Grid, needle: &Grid, bound: f64) -> Vec<Level> {
+    let needle_sums = SummedArea::new(needle.width, needle.height, |x, y| {
+        f64::from(needle.channel_sum(x, y))
+    });
+    let mut levels: Vec<Level> = Vec::new();
+    let mut cell = 2;
+    while levels.len() < MAX_LEVELS && needle.width / cell >= 2 && needle.height / cell >= 2 {
+        let haystack_blocks = match levels.last() {
+            Some(finer) => finer.haystack.downsampled(),
+            None => Blocks::from_grid(haystack),
+        };
+        // Summing over a block scales the per pixel bound on the difference
+        // of channel sums by the number of pixels in it.
+        let max_delta = ((cell * cell) as f64 * bound + 1e-6).floor() as u32;
+        let aligned =
+            Blocks::from_summed_area(&needle_sums, needle.width, needle.height, cell, (0, 0));
+        let phases = if max_delta == 0 || f64::from(max_delta) < aligned.spread() {
+            (0..cell * cell)
+                .map(|i| {
+                    Blocks::from_summed_area(
+                        &needle_sums,
+                        needle.width,
+                        needle.height,
+                        cell,
+                        (i % cell, i / cell),
+                    )
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        levels.push(Level {
+            cell,
+            haystack: haystack_blocks,
+            phases,
+            max_delta,
+        });
+        cell *= 2;
+    }
+    levels.retain(|level| !level.phases.is_empty());
+    levels.reverse();
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmap::ColorMetric;
+    use crate::pyramid::{Grid, TemplateSearch};
+    use std::borrow::Cow;
+
+    fn grid(width: usize, height: usize, pixel: impl Fn(usize, usize) -> [u8; 4]) -> Grid<'static> {
+        let data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| pixel(x, y))
+            .collect();
+        Grid {
+            width,
+            height,
+            data: Cow::Owned(data),
+        }
+    }
+
+    #[test]
+    fn test_pyramid_search_matches_direct_search() {
+        let noise = |x: usize, y: usize| {
+            let v = ((x % 23) * 7919 + (y % 17) * 104_729) as u32;
+            let v = v.wrapping_mul(2_654_435_761);
+            [(v >> 24) as u8, (v >> 16) as u8 & 0xf0, 200, 255]
+        };
+        let haystack = grid(97, 61, noise);
+        let needle = grid(20, 18, |x, y| noise(x + 23, y + 17));
+        for (tolerance, metric) in [
+            (0.0, ColorMetric::Rgb),
+            (0.05, ColorMetric::Rgb),
+            (0.5, ColorMetric::Rgb),
+            (0.02, ColorMetric::ChannelMax),
+        ] {
+            let search = TemplateSearch::with_metric(&haystack, &needle, tolerance, None, metric);
+            if tolerance == 0.0 {
+                assert_eq!(search.levels.len(), 3);
+            }
+            let candidates = search.candidates(0..haystack.width, 0..haystack.height);
+            let (search, candidates) = (&search, &candidates);
+            let found: Vec<(usize, usize)> = (0..haystack.width)
+                .flat_map(|x| {
+                    candidates
+                        .in_column(x, 0..haystack.height)
+                        .filter(move |&y| search.is_match(x, y))
+                        .map(move |y| (x, y))
+                })
+                .collect();
+            let expected: Vec<(usize, usize)> = (0..=haystack.width - needle.width)
+                .flat_map(|x| (0..=haystack.height - needle.height).map(move |y| (x, y)))
+                .filter(|&(x, y)| {
+                    (0..needle.height).all(|needle_y| {
+                        (0..needle.width).all(|needle_x| {
+                            let c1 = needle.get(needle_x, needle_y);
+                            let c2 = haystack.get(x + needle_x, y + needle_y);
+                            metric.matches(c1, c2, tolerance)
+                        })
+                    })
+                })
+                .collect();
+            assert!(expected.contains(&(23, 17)));
+            assert_eq!(found, expected, "tolerance {}", tolerance);
+        }
+    }
+}
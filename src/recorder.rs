@@ -0,0 +1,404 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! This module contains functions for recording a portion of the screen and
+//! saving the recording as an animated GIF or APNG, or as a sequence of PNGs.
+//!
+//! Recordings are kept in memory until saved, so long recordings of large
+//! areas can use a lot of it. A ring-buffer recorder only keeps the last few
+//! seconds, which is useful for attaching a recording to a failed automation
+//! run:
+//!
+//! ```rust,ignore
+//! let rect = Rect::new(Point::ZERO, screen::size());
+//! let recorder = Recorder::start_ring(rect, 10.0, 15.0);
+//! if run_automation().is_err() {
+//!     recorder.finish().save("failure.gif", RecordingFormat::Gif)?;
+//! }
+//! ```
+use crate::bitmap::{self, Bitmap};
+use crate::geometry::Rect;
+use crate::stream::{Frame, ScreenStream};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::error::{ParameterError, ParameterErrorKind};
+use image::{Delay, ImageError, ImageResult};
+use std::fmt::Write as _;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Name of the manifest written alongside a PNG sequence.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Speed passed to the GIF encoder when quantizing frames, trading some color
+/// accuracy for much faster encoding than the default.
+const GIF_SPEED: i32 = 10;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordingFormat {
+    /// An animated GIF, limited to 256 colors per frame.
+    Gif,
+
+    /// An animated PNG, which keeps colors exact.
+    Apng,
+
+    /// A directory of numbered PNGs, `frame-00000.png` onwards, with a
+    /// `manifest.json` listing the timing of each frame.
+    PngSequence,
+}
+
+/// Records a portion of the screen on a background thread.
+#[derive(Debug)]
+pub struct Recorder {
+    stream: ScreenStream,
+    fps: f64,
+
+    /// Length of the ring buffer, if only recent frames are kept.
+    window: Option<Duration>,
+}
+
+/// Frames captured by a `Recorder`.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    pub frames: Vec<Frame>,
+
+    /// Frame rate the recording was captured at.
+    pub fps: f64,
+}
+
+impl Recorder {
+    /// Starts recording `rect` at `fps` frames per second, using
+    /// `capture_screen_portion`. Recording stops on its own after
+    /// `max_duration` seconds if given.
+    ///
+    /// Panics if `fps` or `max_duration` is not positive.
+    pub fn start(rect: Rect, fps: f64, max_duration: Option<f64>) -> Recorder {
+        let limit = max_duration.map(|seconds| {
+            assert!(
+                seconds.is_finite() && seconds > 0.0,
+                "Maximum duration must be positive, got {}.",
+                seconds
+            );
+            Duration::from_secs_f64(seconds)
+        });
+        Recorder::with_capture(fps, limit, None, move || {
+            bitmap::capture_screen_portion(rect)
+        })
+    }
+
+    /// Starts recording `rect` at `fps` frames per second, keeping only the
+    /// frames captured in the last `seconds` seconds.
+    ///
+    /// Panics if `fps` or `seconds` is not positive.
+    pub fn start_ring(rect: Rect, fps: f64, seconds: f64) -> Recorder {
+        assert!(
+            seconds.is_finite() && seconds > 0.0,
+            "Ring buffer length must be positive, got {}.",
+            seconds
+        );
+        Recorder::with_capture(
+            fps,
+            None,
+            Some(Duration::from_secs_f64(seconds)),
+            move || bitmap::capture_screen_portion(rect),
+        )
+    }
+
+    fn with_capture<F>(
+        fps: f64,
+        limit: Option<Duration>,
+        window: Option<Duration>,
+        capture: F,
+    ) -> Recorder
+    where
+        F: FnMut() -> ImageResult<Bitmap> + Send + 'static,
+    {
+        let capacity = match window {
+            Some(window) => (window.as_secs_f64() * fps).ceil().max(1.0) as usize,
+            None => usize::MAX,
+        };
+        Recorder {
+            stream: ScreenStream::with_capture(fps, capacity, limit, capture),
+            fps,
+            window,
+        }
+    }
+
+    /// Returns true until recording stops, either because the maximum
+    /// duration elapsed or a capture failed.
+    pub fn is_recording(&self) -> bool {
+        !self.stream.is_stopped()
+    }
+
+    /// Returns the error that stopped the recording, if a capture failed.
+    /// The frames captured before the failure are still recorded.
+    pub fn take_error(&self) -> Option<ImageError> {
+        self.stream.take_error()
+    }
+
+    /// Returns the frames recorded so far without stopping.
+    pub fn snapshot(&self) -> Recording {
+        self.recording(self.stream.buffered_frames())
+    }
+
+    /// Stops recording and returns the recorded frames.
+    pub fn finish(mut self) -> Recording {
+        self.stream.stop();
+        let frames = self.stream.by_ref().collect();
+        self.recording(frames)
+    }
+
+    fn recording(&self, mut frames: Vec<Frame>) -> Recording {
+        if let (Some(window), Some(last)) = (self.window, frames.last()) {
+            let newest = last.elapsed;
+            frames.retain(|frame| newest - frame.elapsed < window);
+        }
+        Recording {
+            frames,
+            fps: self.fps,
+        }
+    }
+}
+
+impl Recording {
+    /// Returns the time from the start of the first frame to the end of the
+    /// last.
+    pub fn duration(&self) -> Duration {
+        self.delays().iter().sum()
+    }
+
+    /// Returns how long each frame is shown for, based on when the next frame
+    /// was captured. The last frame is shown for one frame interval.
+    pub fn delays(&self) -> Vec<Duration> {
+        let interval = Duration::from_secs_f64(1.0 / self.fps);
+        let mut delays: Vec<Duration> = self
+            .frames
+            .windows(2)
+            .map(|pair| pair[1].elapsed - pair[0].elapsed)
+            .collect();
+        if !self.frames.is_empty() {
+            delays.push(interval);
+        }
+        delays
+    }
+
+    /// Saves the recording to `path` in the given format. For
+    /// `RecordingFormat::PngSequence`, `path` is the directory to write to
+    /// and is created if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: RecordingFormat) -> ImageResult<()> {
+        match format {
+            RecordingFormat::Gif => self.save_gif(path),
+            RecordingFormat::Apng => self.save_apng(path),
+            RecordingFormat::PngSequence => self.save_png_sequence(path).map(|_| ()),
+        }
+    }
+
+    /// Saves the recording as an animated GIF that loops forever.
+    pub fn save_gif<P: AsRef<Path>>(&self, path: P) -> ImageResult<()> {
+        self.check_frames()?;
+        let file = std::fs::File::create(path)?;
+        let mut encoder = GifEncoder::new_with_speed(BufWriter::new(file), GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(self.frames.iter().zip(self.delays()).map(|(frame, delay)| {
+            image::Frame::from_parts(
+                frame.bitmap.image.to_rgba8(),
+                0,
+                0,
+                Delay::from_saturating_duration(delay),
+            )
+        }))
+    }
+
+    /// Saves the recording as an animated PNG that loops forever.
+    pub fn save_apng<P: AsRef<Path>>(&self, path: P) -> ImageResult<()> {
+        let (width, height) = self.check_frames()?;
+        let file = std::fs::File::create(path)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .set_animated(self.frames.len() as u32, 0)
+            .map_err(bitmap::png_encoding_error)?;
+        let mut writer = encoder.write_header().map_err(bitmap::png_encoding_error)?;
+        for (frame, delay) in self.frames.iter().zip(self.delays()) {
+            let milliseconds = delay.as_millis().min(u128::from(u16::MAX)) as u16;
+            writer
+                .set_frame_delay(milliseconds, 1000)
+                .map_err(bitmap::png_encoding_error)?;
+            writer
+                .write_image_data(frame.bitmap.image.to_rgba8().as_raw())
+                .map_err(bitmap::png_encoding_error)?;
+        }
+        writer.finish().map_err(bitmap::png_encoding_error)
+    }
+
+    /// Saves each frame to `dir` as a numbered PNG using `Bitmap::save`,
+    /// along with a `manifest.json` listing the file, index, and capture time
+    /// and delay in milliseconds of each frame. Returns the path of the
+    /// manifest.
+    pub fn save_png_sequence<P: AsRef<Path>>(&self, dir: P) -> ImageResult<PathBuf> {
+        self.check_frames()?;
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let mut manifest = String::new();
+        let _ = writeln!(manifest, "{{");
+        let _ = writeln!(manifest, "  \"fps\": {},", self.fps);
+        let _ = writeln!(manifest, "  \"scale\": {},", self.frames[0].bitmap.scale);
+        let _ = writeln!(manifest, "  \"frames\": [");
+        for (position, (frame, delay)) in self.frames.iter().zip(self.delays()).enumerate() {
+            let name = format!("frame-{:05}.png", position);
+            frame.bitmap.save(dir.join(&name))?;
+            let separator = if position + 1 < self.frames.len() {
+                ","
+            } else {
+                ""
+            };
+            let _ = writeln!(
+                manifest,
+                "    {{ \"file\": \"{}\", \"index\": {}, \"elapsed_ms\": {:.3}, \"delay_ms\": {:.3} }}{}",
+                name,
+                frame.index,
+                frame.elapsed.as_secs_f64() * 1000.0,
+                delay.as_secs_f64() * 1000.0,
+                separator
+            );
+        }
+        let _ = writeln!(manifest, "  ]");
+        let _ = writeln!(manifest, "}}");
+
+        let path = dir.join(MANIFEST_NAME);
+        std::fs::write(&path, manifest)?;
+        Ok(path)
+    }
+
+    /// Returns the size in pixels shared by every frame, or an error if there
+    /// are no frames or their sizes differ.
+    fn check_frames(&self) -> ImageResult<(u32, u32)> {
+        let Some(first) = self.frames.first() else {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::Generic("Recording has no frames".to_string()),
+            )));
+        };
+        let size = (first.bitmap.image.width(), first.bitmap.image.height());
+        if self
+            .frames
+            .iter()
+            .any(|frame| (frame.bitmap.image.width(), frame.bitmap.image.height()) != size)
+        {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch,
+            )));
+        }
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmap::Bitmap;
+    use crate::recorder::{MANIFEST_NAME, Recorder, Recording, RecordingFormat};
+    use crate::stream::Frame;
+    use image::codecs::gif::GifDecoder;
+    use image::{AnimationDecoder, DynamicImage, Rgba, RgbaImage};
+    use std::io::BufReader;
+    use std::time::{Duration, Instant};
+
+    fn solid_bitmap(value: u8) -> Bitmap {
+        let image = RgbaImage::from_pixel(4, 3, Rgba([value, 255 - value, 0, 255]));
+        Bitmap::new(DynamicImage::ImageRgba8(image), None)
+    }
+
+    #[test]
+    fn test_recorder() {
+        let recorder = Recorder::with_capture(100.0, Some(Duration::from_millis(50)), None, || {
+            Ok(solid_bitmap(0))
+        });
+        while recorder.is_recording() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let recording = recorder.finish();
+        assert!(!recording.frames.is_empty());
+        assert!(recording.frames.len() <= 5);
+        assert!(
+            recording
+                .frames
+                .iter()
+                .all(|frame| frame.elapsed < Duration::from_millis(50))
+        );
+
+        let recorder = Recorder::with_capture(200.0, None, Some(Duration::from_millis(30)), || {
+            Ok(solid_bitmap(0))
+        });
+        std::thread::sleep(Duration::from_millis(150));
+        let snapshot = recorder.snapshot();
+        assert!(recorder.is_recording());
+        assert!(!snapshot.frames.is_empty());
+        assert!(snapshot.frames.len() <= 6);
+        assert!(snapshot.frames[0].elapsed >= Duration::from_millis(50));
+        assert!(recorder.finish().frames.len() <= 6);
+    }
+
+    #[test]
+    fn test_save_recording() {
+        let start = Instant::now();
+        let recording = Recording {
+            frames: (0..3)
+                .map(|index| Frame {
+                    bitmap: solid_bitmap(index as u8 * 100),
+                    timestamp: start + Duration::from_millis(index * 200),
+                    elapsed: Duration::from_millis(index * 200),
+                    index,
+                })
+                .collect(),
+            fps: 5.0,
+        };
+        assert_eq!(recording.duration(), Duration::from_millis(600));
+
+        let dir = std::env::temp_dir().join(format!("autopilot-recording-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let gif_path = dir.join("recording.gif");
+        recording.save(&gif_path, RecordingFormat::Gif).unwrap();
+        let file = BufReader::new(std::fs::File::open(&gif_path).unwrap());
+        let frames = GifDecoder::new(file)
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1].delay().numer_denom_ms(), (200, 1));
+
+        let apng_path = dir.join("recording.png");
+        recording.save(&apng_path, RecordingFormat::Apng).unwrap();
+        let file = BufReader::new(std::fs::File::open(&apng_path).unwrap());
+        let reader = png::Decoder::new(file).read_info().unwrap();
+        let control = reader.info().animation_control.unwrap();
+        assert_eq!(control.num_frames, 3);
+        assert_eq!(control.num_plays, 0);
+
+        let sequence_dir = dir.join("frames");
+        recording
+            .save(&sequence_dir, RecordingFormat::PngSequence)
+            .unwrap();
+        assert_eq!(
+            Bitmap::open(sequence_dir.join("frame-00002.png")).unwrap(),
+            recording.frames[2].bitmap
+        );
+        let manifest = std::fs::read_to_string(sequence_dir.join(MANIFEST_NAME)).unwrap();
+        assert!(manifest.contains(
+            "{ \"file\": \"frame-00001.png\", \"index\": 1, \"elapsed_ms\": 200.000, \"delay_ms\": 200.000 },"
+        ));
+
+        let empty = Recording {
+            frames: Vec::new(),
+            fps: 5.0,
+        };
+        assert!(empty.save_gif(dir.join("empty.gif")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
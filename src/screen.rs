@@ -16,6 +16,41 @@ use core_graphics::display::CGDisplay;
 #[cfg(target_os = "linux")]
 use crate::internal;
 
+/// A monitor attached to the system, as returned by `displays`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Display {
+    /// Identifier assigned to the display by the system. It is unique among
+    /// the displays currently attached, but may change when they are
+    /// reconfigured.
+    pub id: u64,
+
+    /// Name of the display, e.g. the name of its output on X11.
+    pub name: String,
+
+    /// Bounds of the display in points, in the coordinate space shared by
+    /// all displays that is used by `mouse` and `capture_screen_portion`.
+    pub bounds: Rect,
+
+    /// Scale of the display, i.e. how many pixels are in a point. On X11,
+    /// where the scale is set for the whole desktop, every display has the
+    /// same scale as returned by `scale`. Windows may set a different DPI
+    /// for each monitor, but since autopilot is only system DPI aware,
+    /// Windows scales every monitor to the system DPI and each display also
+    /// has the scale returned by `scale`.
+    pub scale: f64,
+
+    /// Whether this is the main display.
+    pub is_main: bool,
+}
+
+/// Returns every active display, starting with the main display.
+///
+/// On X11, displays are read from XRandR. If the server does not support
+/// XRandR 1.5, a single display covering the whole screen is returned.
+pub fn displays() -> Vec<Display> {
+    ordered_displays(system_displays())
+}
+
 /// Returns the size of the main screen in points.
 pub fn size() -> Size {
     system_size()
@@ -26,14 +61,25 @@ pub fn scale() -> f64 {
     system_scale()
 }
 
-/// Returns whether the given point is inside the main screen boundaries.
+/// Returns whether the given point is inside the main screen or any other
+/// display.
 pub fn is_point_visible(point: Point) -> bool {
     Rect::new(Point::ZERO, size()).is_point_visible(point)
+        || displays()
+            .iter()
+            .any(|display| display.bounds.is_point_visible(point))
 }
 
-/// Returns whether the given rect is inside the main screen boundaries.
+/// Returns whether the given rect is inside the main screen, or covered by
+/// the displays. Rects that reach into gaps between displays, e.g. in
+/// L-shaped layouts, are not visible.
 pub fn is_rect_visible(rect: Rect) -> bool {
-    Rect::new(Point::ZERO, size()).is_rect_visible(rect)
+    if rect.size.width <= 0.0 || rect.size.height <= 0.0 {
+        return is_point_visible(rect.origin);
+    }
+    let mut bounds = vec![Rect::new(Point::ZERO, size())];
+    bounds.extend(displays().iter().map(|display| display.bounds));
+    is_rect_covered(rect, &bounds)
 }
 
 /// A convenience method that returns the RGB color at the given point on the
@@ -43,6 +89,82 @@ pub fn get_color(point: Point) -> ImageResult<Rgba<u8>> {
     Ok(bmp.image.get_pixel(0, 0))
 }
 
+/// Moves the main display to the front, marking the first display as the
+/// main one if the system did not.
+fn ordered_displays(mut displays: Vec<Display>) -> Vec<Display> {
+    if !displays.iter().any(|display| display.is_main)
+        && let Some(first) = displays.first_mut()
+    {
+        first.is_main = true;
+    }
+    displays.sort_by_key(|display| !display.is_main);
+    displays
+}
+
+/// Returns whether every part of `rect` lies inside at least one of
+/// `bounds`.
+fn is_rect_covered(rect: Rect, bounds: &[Rect]) -> bool {
+    let mut uncovered = vec![rect];
+    for bound in bounds {
+        uncovered = uncovered
+            .into_iter()
+            .flat_map(|piece| subtract_rect(piece, *bound))
+            .collect();
+        if uncovered.is_empty() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Returns the parts of `rect` outside of `other`, as up to four rects.
+fn subtract_rect(rect: Rect, other: Rect) -> Vec<Rect> {
+    let Some(overlap) = rect.intersection(other) else {
+        return vec![rect];
+    };
+    let (left, right) = (rect.origin.x, rect.max_x());
+    let (top, bottom) = (rect.origin.y, rect.max_y());
+    let (inner_left, inner_right) = (overlap.origin.x, overlap.max_x());
+    let (inner_top, inner_bottom) = (overlap.origin.y, overlap.max_y());
+    let piece = |min_x: f64, min_y: f64, max_x: f64, max_y: f64| {
+        Rect::new(
+            Point::new(min_x, min_y),
+            Size::new(max_x - min_x, max_y - min_y),
+        )
+    };
+    [
+        piece(left, top, right, inner_top),
+        piece(left, inner_bottom, right, bottom),
+        piece(left, inner_top, inner_left, inner_bottom),
+        piece(inner_right, inner_top, right, inner_bottom),
+    ]
+    .into_iter()
+    .filter(|piece| piece.size.width > 0.0 && piece.size.height > 0.0)
+    .collect()
+}
+
+#[cfg(target_os = "macos")]
+fn system_displays() -> Vec<Display> {
+    CGDisplay::active_displays()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|id| {
+            let display = CGDisplay::new(id);
+            let bounds = display.bounds();
+            let scale = display.display_mode().map_or(1.0, |mode| {
+                mode.pixel_height() as f64 / mode.height() as f64
+            });
+            Display {
+                id: u64::from(id),
+                name: format!("Display {}", id),
+                bounds: Rect::new(Point::from(bounds.origin), Size::from(bounds.size)),
+                scale,
+                is_main: display.is_main(),
+            }
+        })
+        .collect()
+}
+
 #[cfg(target_os = "macos")]
 fn system_size() -> Size {
     Size::from(CGDisplay::main().bounds().size)
@@ -54,6 +176,68 @@ fn system_scale() -> f64 {
     mode.pixel_height() as f64 / mode.height() as f64
 }
 
+#[cfg(windows)]
+fn system_displays() -> Vec<Display> {
+    use winapi::shared::minwindef::{BOOL, LPARAM, TRUE};
+    use winapi::shared::windef::{HDC, HMONITOR, LPRECT};
+    use winapi::um::winuser::{EnumDisplayMonitors, GetMonitorInfoW};
+    use winapi::um::winuser::{MONITORINFO, MONITORINFOEXW, MONITORINFOF_PRIMARY};
+
+    unsafe extern "system" fn push_monitor(
+        monitor: HMONITOR,
+        _: HDC,
+        _: LPRECT,
+        data: LPARAM,
+    ) -> BOOL {
+        let monitors = unsafe { &mut *(data as *mut Vec<HMONITOR>) };
+        monitors.push(monitor);
+        TRUE
+    }
+
+    let mut monitors: Vec<HMONITOR> = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            Some(push_monitor),
+            &mut monitors as *mut Vec<HMONITOR> as LPARAM,
+        )
+    };
+
+    let scale_factor = scale();
+    monitors
+        .into_iter()
+        .filter_map(|monitor| {
+            let mut info: MONITORINFOEXW = unsafe { std::mem::zeroed() };
+            info.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+            let info_ptr = &mut info as *mut MONITORINFOEXW as *mut MONITORINFO;
+            if unsafe { GetMonitorInfoW(monitor, info_ptr) } == 0 {
+                return None;
+            }
+            let rect = info.rcMonitor;
+            let name_length = info
+                .szDevice
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(info.szDevice.len());
+            Some(Display {
+                id: monitor as usize as u64,
+                name: String::from_utf16_lossy(&info.szDevice[..name_length]),
+                bounds: Rect::new(
+                    Point::from_pixel(f64::from(rect.left), f64::from(rect.top), scale_factor),
+                    Size::new(
+                        f64::from(rect.right - rect.left),
+                        f64::from(rect.bottom - rect.top),
+                    )
+                    .scaled(1.0 / scale_factor),
+                ),
+                scale: scale_factor,
+                is_main: info.dwFlags & MONITORINFOF_PRIMARY != 0,
+            })
+        })
+        .collect()
+}
+
 #[cfg(windows)]
 fn system_size() -> Size {
     use winapi::um::winuser::{GetSystemMetrics, SM_CXSCREEN, SM_CYSCREEN};
@@ -93,6 +277,76 @@ fn system_scale() -> f64 {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn system_displays() -> Vec<Display> {
+    use std::ffi::CStr;
+    let scale_factor = scale();
+    let monitors = internal::X_MAIN_DISPLAY.with(|display| unsafe {
+        let display = display.as_ptr();
+        let (mut event_base, mut error_base) = (0, 0);
+        let (mut major, mut minor) = (0, 0);
+        // XRRGetMonitors was added in XRandR 1.5.
+        if x11::xrandr::XRRQueryExtension(display, &mut event_base, &mut error_base) == 0
+            || x11::xrandr::XRRQueryVersion(display, &mut major, &mut minor) == 0
+            || (major, minor) < (1, 5)
+        {
+            return Vec::new();
+        }
+
+        let mut count = 0;
+        let root = x11::xlib::XDefaultRootWindow(display);
+        let monitors = x11::xrandr::XRRGetMonitors(display, root, 1, &mut count);
+        if monitors.is_null() {
+            return Vec::new();
+        }
+        defer!({
+            x11::xrandr::XRRFreeMonitors(monitors);
+        });
+        std::slice::from_raw_parts(monitors, count.max(0) as usize)
+            .iter()
+            .map(|monitor| {
+                let name_ptr = x11::xlib::XGetAtomName(display, monitor.name);
+                let name = if name_ptr.is_null() {
+                    String::new()
+                } else {
+                    let name = CStr::from_ptr(name_ptr).to_string_lossy().into_owned();
+                    x11::xlib::XFree(name_ptr as *mut libc::c_void);
+                    name
+                };
+                // Atoms are not 64 bits wide on every platform.
+                #[allow(clippy::unnecessary_cast)]
+                let id = monitor.name as u64;
+                Display {
+                    id,
+                    name,
+                    bounds: Rect::new(
+                        Point::from_pixel(
+                            f64::from(monitor.x),
+                            f64::from(monitor.y),
+                            scale_factor,
+                        ),
+                        Size::new(f64::from(monitor.width), f64::from(monitor.height))
+                            .scaled(1.0 / scale_factor),
+                    ),
+                    scale: scale_factor,
+                    is_main: monitor.primary != 0,
+                }
+            })
+            .collect()
+    });
+    if !monitors.is_empty() {
+        return monitors;
+    }
+
+    vec![Display {
+        id: 0,
+        name: String::from("default"),
+        bounds: Rect::new(Point::ZERO, size()),
+        scale: scale_factor,
+        is_main: true,
+    }]
+}
+
 #[cfg(target_os = "linux")]
 fn system_size() -> Size {
     internal::X_MAIN_DISPLAY.with(|display| unsafe {
@@ -118,3 +372,75 @@ use winapi::shared::windef::HWND;
 type SetProcessDPIAwareSignature = unsafe extern "C" fn();
 #[cfg(windows)]
 type GetDPIForWindowSignature = unsafe extern "C" fn(HWND) -> libc::c_uint;
+
+#[cfg(test)]
+mod tests {
+    use crate::geometry::{Point, Rect, Size};
+    use crate::screen::{is_rect_covered, ordered_displays, Display};
+
+    #[test]
+    fn test_ordered_displays() {
+        let display = |id: u64, x: f64, is_main: bool| Display {
+            id,
+            name: format!("DP-{}", id),
+            bounds: Rect::new(Point::new(x, 0.0), Size::new(1920.0, 1080.0)),
+            scale: 1.0,
+            is_main,
+        };
+        let ids = |displays: Vec<Display>| {
+            displays
+                .iter()
+                .map(|display| (display.id, display.is_main))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(ordered_displays(vec![
+                display(1, -1920.0, false),
+                display(2, 0.0, true),
+                display(3, 1920.0, false),
+            ])),
+            [(2, true), (1, false), (3, false)]
+        );
+        assert_eq!(
+            ids(ordered_displays(vec![display(1, 0.0, false), display(2, 1920.0, false)])),
+            [(1, true), (2, false)]
+        );
+        assert!(ordered_displays(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_is_rect_covered() {
+        let rect = |x: f64, y: f64, width: f64, height: f64| {
+            Rect::new(Point::new(x, y), Size::new(width, height))
+        };
+        // An L-shaped layout with a display below and to the right of the
+        // main one, leaving a gap in the bottom left of their union.
+        let bounds = [
+            rect(0.0, 0.0, 1920.0, 1080.0),
+            rect(1920.0, 1080.0, 1280.0, 1024.0),
+        ];
+        assert!(is_rect_covered(rect(100.0, 100.0, 200.0, 200.0), &bounds));
+        assert!(is_rect_covered(rect(2000.0, 1500.0, 200.0, 200.0), &bounds));
+        assert!(is_rect_covered(
+            rect(1920.0, 1080.0, 1280.0, 1024.0),
+            &bounds
+        ));
+        assert!(!is_rect_covered(rect(100.0, 1500.0, 200.0, 200.0), &bounds));
+        assert!(!is_rect_covered(
+            rect(1800.0, 1000.0, 200.0, 200.0),
+            &bounds
+        ));
+        assert!(!is_rect_covered(rect(3000.0, 0.0, 100.0, 100.0), &bounds));
+
+        // Side by side displays cover rects across their shared edge, even
+        // when they overlap.
+        let bounds = [
+            rect(0.0, 0.0, 1920.0, 1080.0),
+            rect(1800.0, 0.0, 1920.0, 1080.0),
+        ];
+        assert!(is_rect_covered(rect(1700.0, 500.0, 400.0, 200.0), &bounds));
+        assert!(is_rect_covered(rect(0.0, 0.0, 3720.0, 1080.0), &bounds));
+        assert!(!is_rect_covered(rect(0.0, 0.0, 3720.0, 1081.0), &bounds));
+        assert!(!is_rect_covered(rect(0.0, 0.0, 100.0, 100.0), &[]));
+    }
+}
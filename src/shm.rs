@@ -0,0 +1,216 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! Screen capture on X11 through a shared memory segment, using the MIT-SHM
+//! extension.
+//!
+//! `XGetImage` sends every captured pixel over the X socket, which dominates
+//! the cost of repeated captures. With MIT-SHM, the server writes pixels
+//! straight into memory shared with the client instead. The segment is kept
+//! between captures of the same size, and the extension is only probed once
+//! per thread.
+use crate::internal;
+use std::cell::RefCell;
+use std::ptr::NonNull;
+use x11::xlib::{Display, XImage};
+use x11::xshm::XShmSegmentInfo;
+
+enum State {
+    /// The extension has not been probed yet on this thread.
+    Unknown,
+
+    /// The extension is missing or unusable, e.g. on a remote display.
+    Unavailable,
+
+    /// The extension works, and holds the image of the last capture if any.
+    Available(Option<SharedImage>),
+}
+
+thread_local!(static STATE: RefCell<State> = const { RefCell::new(State::Unknown) });
+
+/// Captures the given portion of the root window, in pixels, and passes the
+/// resulting image to `f`. Returns `None` without calling `f` if the
+/// extension is unavailable or the capture failed, in which case callers
+/// should fall back to `XGetImage`.
+pub fn capture<T>(
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    f: impl FnOnce(&XImage) -> T,
+) -> Option<T> {
+    internal::X_MAIN_DISPLAY.with(|display| {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if let State::Unknown = *state {
+                let available = unsafe { x11::xshm::XShmQueryExtension(display.as_ptr()) } != 0;
+                *state = if available {
+                    State::Available(None)
+                } else {
+                    State::Unavailable
+                };
+            }
+            let State::Available(cached) = &mut *state else {
+                return None;
+            };
+
+            if !cached
+                .as_ref()
+                .is_some_and(|image| image.width == width && image.height == height)
+            {
+                // Free the old segment before allocating its replacement.
+                *cached = None;
+                match SharedImage::new(**display, width, height) {
+                    Some(image) => *cached = Some(image),
+                    None => {
+                        *state = State::Unavailable;
+                        return None;
+                    }
+                }
+            }
+
+            let image = cached.as_mut()?;
+            if image.capture(x, y) {
+                Some(f(unsafe { image.image.as_ref() }))
+            } else {
+                *cached = None;
+                None
+            }
+        })
+    })
+}
+
+/// Frees the shared memory segment of this thread, if any. Called before the
+/// thread's X connection is closed.
+pub fn release() {
+    // The state may already have been destroyed if the thread is exiting.
+    let _ = STATE.try_with(|state| {
+        if let Ok(mut state) = state.try_borrow_mut()
+            && let State::Available(cached) = &mut *state
+        {
+            *cached = None;
+        }
+    });
+}
+
+/// An `XImage` backed by a shared memory segment attached to the X server.
+struct SharedImage {
+    display: NonNull<Display>,
+    image: NonNull<XImage>,
+    segment: Box<XShmSegmentInfo>,
+    width: u32,
+    height: u32,
+}
+
+impl SharedImage {
+    fn new(display: NonNull<Display>, width: u32, height: u32) -> Option<SharedImage> {
+        let display_ptr = display.as_ptr();
+        let mut segment = Box::new(XShmSegmentInfo {
+            shmseg: 0,
+            shmid: -1,
+            shmaddr: std::ptr::null_mut(),
+            readOnly: 0,
+        });
+        unsafe {
+            let screen = x11::xlib::XDefaultScreen(display_ptr);
+            let image = NonNull::new(x11::xshm::XShmCreateImage(
+                display_ptr,
+                x11::xlib::XDefaultVisual(display_ptr, screen),
+                x11::xlib::XDefaultDepth(display_ptr, screen) as u32,
+                x11::xlib::ZPixmap,
+                std::ptr::null_mut(),
+                &mut *segment,
+                width,
+                height,
+            ))?;
+            let length = (*image.as_ptr()).bytes_per_line as usize * height as usize;
+            segment.shmid = libc::shmget(libc::IPC_PRIVATE, length, libc::IPC_CREAT | 0o600);
+            if segment.shmid == -1 {
+                destroy_image(image);
+                return None;
+            }
+            segment.shmaddr = libc::shmat(segment.shmid, std::ptr::null(), 0) as *mut libc::c_char;
+            let mapped = segment.shmaddr as isize != -1;
+            let attached = mapped
+                && internal::trap_x_errors(display_ptr, || {
+                    x11::xshm::XShmAttach(display_ptr, &mut *segment);
+                });
+            // Mark the segment for removal once both sides have attached, so
+            // that it is freed when detached even if the process exits
+            // abruptly.
+            libc::shmctl(segment.shmid, libc::IPC_RMID, std::ptr::null_mut());
+            if !attached {
+                if mapped {
+                    libc::shmdt(segment.shmaddr as *const libc::c_void);
+                }
+                destroy_image(image);
+                return None;
+            }
+            (*image.as_ptr()).data = segment.shmaddr;
+
+            Some(SharedImage {
+                display,
+                image,
+                segment,
+                width,
+                height,
+            })
+        }
+    }
+
+    /// Copies the portion of the root window at `(x, y)` into the image.
+    fn capture(&mut self, x: i32, y: i32) -> bool {
+        let display = self.display.as_ptr();
+        let image = self.image.as_ptr();
+        unsafe {
+            let root = x11::xlib::XDefaultRootWindow(display);
+            let mut succeeded = false;
+            internal::trap_x_errors(display, || {
+                succeeded = XShmGetImage(
+                    display,
+                    root,
+                    image,
+                    x,
+                    y,
+                    x11::xlib::XAllPlanes(),
+                ) != 0;
+            }) && succeeded
+        }
+    }
+}
+
+impl Drop for SharedImage {
+    fn drop(&mut self) {
+        unsafe {
+            x11::xshm::XShmDetach(self.display.as_ptr(), &mut *self.segment);
+            x11::xlib::XSync(self.display.as_ptr(), 0);
+            libc::shmdt(self.segment.shmaddr as *const libc::c_void);
+            destroy_image(self.image);
+        }
+    }
+}
+
+/// Destroys an image whose data is not owned by Xlib.
+unsafe fn destroy_image(image: NonNull<XImage>) {
+    unsafe {
+        (*image.as_ptr()).data = std::ptr::null_mut();
+        x11::xlib::XDestroyImage(image.as_ptr());
+    }
+}
+
+// Declared here since the `x11` crate declares the plane mask as a 32-bit
+// integer rather than an `unsigned long`.
+unsafe extern "C" {
+    unsafe fn XShmGetImage(
+        display: *mut Display,
+        drawable: x11::xlib::Drawable,
+        image: *mut XImage,
+        x: libc::c_int,
+        y: libc::c_int,
+        plane_mask: libc::c_ulong,
+    ) -> x11::xlib::Bool;
+}
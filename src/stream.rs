@@ -0,0 +1,405 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! This module contains `ScreenStream`, which captures a portion of the
+//! screen continuously on a background thread.
+use crate::bitmap::{self, Bitmap};
+use crate::geometry::Rect;
+use image::{ImageError, ImageResult};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Number of frames buffered by default.
+const DEFAULT_BUFFER: usize = 8;
+
+/// A bitmap captured by a `ScreenStream`.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub bitmap: Bitmap,
+
+    /// When the capture of the frame started.
+    pub timestamp: Instant,
+
+    /// Time from the start of the stream to `timestamp`.
+    pub elapsed: Duration,
+
+    /// Position of the frame among all frames captured by the stream,
+    /// starting from 0. Frames dropped from the buffer leave gaps.
+    pub index: u64,
+}
+
+/// Captures a portion of the screen at a target frame rate on a background
+/// thread, buffering the most recent frames.
+///
+/// Captures are scheduled at fixed intervals from the start of the stream,
+/// so the frame rate does not drift. If a capture takes longer than the
+/// interval, the missed captures are skipped rather than made up for. When
+/// the buffer is full, the oldest frame is dropped to make room for the
+/// newest.
+///
+/// Frames can be read with `next_frame` and its variants, or by iterating
+/// over the stream. The stream stops when dropped, when `stop` is called or
+/// when a capture fails, after which the remaining buffered frames can
+/// still be read.
+///
+/// ```rust,ignore
+/// let rect = Rect::new(Point::ZERO, screen::size());
+/// for frame in ScreenStream::new(rect, 10.0, None).take(50) {
+///     println!("{:?}: {}", frame.elapsed, frame.bitmap.size);
+/// }
+/// ```
+pub struct ScreenStream {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+
+    /// Signaled when a frame is added or the stream stops.
+    frame_ready: Condvar,
+
+    /// Signaled when the stream is asked to stop.
+    stop_requested: Condvar,
+}
+
+struct State {
+    frames: VecDeque<Frame>,
+    capacity: usize,
+    dropped: u64,
+    stopped: bool,
+    error: Option<ImageError>,
+}
+
+impl ScreenStream {
+    /// Starts capturing `rect` using `capture_screen_portion`, at `fps`
+    /// frames per second. At most `buffer` frames are kept before the
+    /// oldest is dropped (defaults to 8).
+    ///
+    /// Panics if `fps` is not positive or `buffer` is zero.
+    pub fn new(rect: Rect, fps: f64, buffer: Option<usize>) -> ScreenStream {
+        let capacity = buffer.unwrap_or(DEFAULT_BUFFER);
+        ScreenStream::with_capture(fps, capacity, None, move || {
+            bitmap::capture_screen_portion(rect)
+        })
+    }
+
+    /// Starts a stream that captures frames by calling `capture`, stopping
+    /// on its own once `limit` has elapsed if given.
+    pub(crate) fn with_capture<F>(
+        fps: f64,
+        capacity: usize,
+        limit: Option<Duration>,
+        capture: F,
+    ) -> ScreenStream
+    where
+        F: FnMut() -> ImageResult<Bitmap> + Send + 'static,
+    {
+        assert!(
+            fps.is_finite() && fps > 0.0,
+            "Frame rate must be positive, got {}.",
+            fps
+        );
+        assert!(capacity > 0, "Buffer must hold at least one frame.");
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                frames: VecDeque::new(),
+                capacity,
+                dropped: 0,
+                stopped: false,
+                error: None,
+            }),
+            frame_ready: Condvar::new(),
+            stop_requested: Condvar::new(),
+        });
+        let interval = Duration::from_secs_f64(1.0 / fps);
+        let thread_shared = Arc::clone(&shared);
+        let thread = std::thread::Builder::new()
+            .name("autopilot-screen-stream".to_string())
+            .spawn(move || run(&thread_shared, interval, limit, capture))
+            .expect("failed to spawn screen stream thread");
+        ScreenStream {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns the oldest buffered frame, waiting for one to be captured if
+    /// the buffer is empty. Returns `None` once the stream has stopped and
+    /// every buffered frame has been read.
+    pub fn next_frame(&self) -> Option<Frame> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                return Some(frame);
+            }
+            if state.stopped {
+                return None;
+            }
+            state = self
+                .shared
+                .frame_ready
+                .wait(state)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+    }
+
+    /// Returns the oldest buffered frame as with `next_frame`, waiting at
+    /// most `timeout` for one to be captured.
+    pub fn next_frame_timeout(&self, timeout: Duration) -> Option<Frame> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                return Some(frame);
+            }
+            let now = Instant::now();
+            if state.stopped || now >= deadline {
+                return None;
+            }
+            state = self
+                .shared
+                .frame_ready
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+
+    /// Returns the oldest buffered frame without waiting, or `None` if the
+    /// buffer is empty.
+    pub fn try_next_frame(&self) -> Option<Frame> {
+        self.shared.lock().frames.pop_front()
+    }
+
+    /// Returns the newest buffered frame without waiting, discarding any
+    /// older ones, or `None` if the buffer is empty.
+    pub fn latest_frame(&self) -> Option<Frame> {
+        let mut state = self.shared.lock();
+        let latest = state.frames.pop_back();
+        state.frames.clear();
+        latest
+    }
+
+    /// Returns copies of the buffered frames without removing them.
+    pub(crate) fn buffered_frames(&self) -> Vec<Frame> {
+        self.shared.lock().frames.iter().cloned().collect()
+    }
+
+    /// Returns the number of frames dropped because the buffer was full.
+    pub fn dropped_frames(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+
+    /// Returns true if the stream has stopped capturing.
+    pub fn is_stopped(&self) -> bool {
+        self.shared.lock().stopped
+    }
+
+    /// Returns the error that stopped the stream, if a capture failed. The
+    /// error is only returned once.
+    pub fn take_error(&self) -> Option<ImageError> {
+        self.shared.lock().error.take()
+    }
+
+    /// Stops capturing and waits for the background thread to finish.
+    /// Frames that were already buffered can still be read.
+    pub fn stop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.stop_requested.notify_all();
+        self.shared.frame_ready.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Iterator for ScreenStream {
+    type Item = Frame;
+
+    /// Returns the next frame as with `next_frame`.
+    fn next(&mut self) -> Option<Frame> {
+        self.next_frame()
+    }
+}
+
+impl Drop for ScreenStream {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl std::fmt::Debug for ScreenStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = self.shared.lock();
+        write!(
+            f,
+            "ScreenStream {{ buffered: {}, dropped: {}, stopped: {} }}",
+            state.frames.len(),
+            state.dropped,
+            state.stopped
+        )
+    }
+}
+
+impl Shared {
+    /// Locks the state, ignoring poisoning since it is never left
+    /// inconsistent.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Captures frames until the stream is stopped, `limit` elapses or a
+/// capture fails.
+fn run<F>(shared: &Shared, interval: Duration, limit: Option<Duration>, mut capture: F)
+where
+    F: FnMut() -> ImageResult<Bitmap>,
+{
+    // Wake any readers however the thread exits, including when `capture`
+    // panics, e.g. because no X display could be opened.
+    scopeguard::defer!({
+        shared.lock().stopped = true;
+        shared.frame_ready.notify_all();
+    });
+    let start = Instant::now();
+    let mut deadline = start;
+    let mut index: u64 = 0;
+    loop {
+        let timestamp = Instant::now();
+        let result = capture();
+
+        let mut state = shared.lock();
+        if state.stopped {
+            return;
+        }
+        match result {
+            Ok(bitmap) => {
+                if state.frames.len() == state.capacity {
+                    state.frames.pop_front();
+                    state.dropped += 1;
+                }
+                state.frames.push_back(Frame {
+                    bitmap,
+                    timestamp,
+                    elapsed: timestamp - start,
+                    index,
+                });
+                shared.frame_ready.notify_all();
+            }
+            Err(err) => {
+                state.error = Some(err);
+                return;
+            }
+        }
+
+        // Skip any captures that were missed rather than bursting to catch
+        // up, keeping the schedule aligned to the start of the stream.
+        let now = Instant::now();
+        loop {
+            deadline += interval;
+            index += 1;
+            if deadline > now {
+                break;
+            }
+        }
+        if limit.is_some_and(|limit| deadline - start >= limit) {
+            return;
+        }
+        while !state.stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = shared
+                .stop_requested
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+        if state.stopped {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmap::Bitmap;
+    use crate::stream::ScreenStream;
+    use image::error::{LimitError, LimitErrorKind};
+    use image::{DynamicImage, ImageError, Rgba, RgbaImage};
+    use std::time::Duration;
+
+    fn solid_bitmap(value: u8) -> Bitmap {
+        let image = RgbaImage::from_pixel(2, 2, Rgba([value, value, value, 255]));
+        Bitmap::new(DynamicImage::ImageRgba8(image), None)
+    }
+
+    #[test]
+    fn test_screen_stream() {
+        let mut count = 0u8;
+        let mut stream = ScreenStream::with_capture(200.0, 3, None, move || {
+            count = count.wrapping_add(1);
+            Ok(solid_bitmap(count))
+        });
+        let first = stream.next_frame().unwrap();
+        let second = stream.next_frame_timeout(Duration::from_secs(5)).unwrap();
+        assert!(second.index > first.index);
+        assert!(second.timestamp > first.timestamp);
+        assert!(second.elapsed > first.elapsed);
+
+        // Frames keep being captured while nobody reads them, dropping the
+        // oldest ones.
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(stream.dropped_frames() > 0);
+        stream.stop();
+        assert!(stream.is_stopped());
+        let buffered: Vec<_> = stream.by_ref().collect();
+        assert_eq!(buffered.len(), 3);
+        assert!(
+            buffered
+                .windows(2)
+                .all(|pair| pair[0].index < pair[1].index)
+        );
+        assert!(stream.next_frame().is_none());
+        assert!(stream.take_error().is_none());
+    }
+
+    #[test]
+    fn test_screen_stream_error() {
+        let mut count = 0;
+        let stream = ScreenStream::with_capture(1000.0, 8, None, move || {
+            count += 1;
+            if count > 2 {
+                Err(ImageError::Limits(LimitError::from_kind(
+                    LimitErrorKind::DimensionError,
+                )))
+            } else {
+                Ok(solid_bitmap(0))
+            }
+        });
+        assert_eq!(stream.take(5).count(), 2);
+
+        let stream = ScreenStream::with_capture(1000.0, 8, None, || {
+            Err(ImageError::Limits(LimitError::from_kind(
+                LimitErrorKind::DimensionError,
+            )))
+        });
+        assert!(stream.next_frame().is_none());
+        assert!(stream.is_stopped());
+        assert!(stream.take_error().is_some());
+        assert!(stream.take_error().is_none());
+
+        let stream = ScreenStream::with_capture(1000.0, 8, None, || panic!("capture failed"));
+        assert!(stream.next_frame().is_none());
+        assert!(stream.is_stopped());
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2018, 2019, 2020 Michael Sanders
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// https://apache.org/licenses/LICENSE-2.0> or the MIT License <LICENSE-MIT or
+// https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+//! Summed-area tables for constant-time sums over rectangular windows.
+
+/// Summed-area table of a value at each point of a grid.
+pub struct SummedArea {
+    width: usize,
+    sums: Vec<f64>,
+}
+
+impl SummedArea {
+    /// Builds the table for a grid of the given size, taking the value at
+    /// each point from `value`.
+    pub fn new<F: Fn(usize, usize) -> f64>(width: usize, height: usize, value: F) -> SummedArea {
+        let stride = width + 1;
+        let mut sums = vec![0.0; stride * (height + 1)];
+        for y in 0..height {
+            let mut row_sum = 0.0;
+            for x in 0..width {
+                row_sum += value(x, y);
+                sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row_sum;
+            }
+        }
+        SummedArea { width, sums }
+    }
+
+    /// Returns the sum of the values in the given window.
+    #[inline]
+    pub fn window(&self, x: usize, y: usize, width: usize, height: usize) -> f64 {
+        let stride = self.width + 1;
+        self.sums[(y + height) * stride + x + width]
+            - self.sums[y * stride + x + width]
+            - self.sums[(y + height) * stride + x]
+            + self.sums[y * stride + x]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::summed_area::SummedArea;
+
+    #[test]
+    fn test_summed_area_window() {
+        let value = |x: usize, y: usize| (x * 3 + y * 5 % 7) as f64;
+        let table = SummedArea::new(9, 6, value);
+        for (x, y, width, height) in [(0, 0, 9, 6), (2, 1, 3, 4), (8, 5, 1, 1), (4, 3, 0, 2)] {
+            let expected: f64 = (y..y + height)
+                .flat_map(|y| (x..x + width).map(move |x| value(x, y)))
+                .sum();
+            assert_eq!(table.window(x, y, width, height), expected);
+        }
+    }
+}
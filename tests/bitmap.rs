@@ -1,6 +1,8 @@
 extern crate autopilot;
 extern crate image;
 use autopilot::bitmap::Bitmap;
+use autopilot::geometry::Rect;
+use autopilot::golden;
 use std::path;
 
 #[test]
@@ -15,6 +17,100 @@ fn find_bitmap() {
     }
 }
 
+#[test]
+fn find_best_bitmap() {
+    let haystack_path = asset_path().join("haystack.png");
+    let haystack = Bitmap::new(image::open(haystack_path).unwrap(), None);
+    for idx in 0..2 {
+        let needle_path = asset_path().join(format!("needle{}.png", idx + 1));
+        let needle = Bitmap::new(image::open(needle_path).unwrap(), None);
+        let expected = haystack.find_bitmap(&needle, None, None, None);
+        let found = haystack.find_best_bitmap(&needle, None).unwrap();
+        assert_eq!(Some(found.rect.origin), expected);
+        assert!(found.score > 0.99);
+    }
+}
+
+#[test]
+fn find_bitmap_match() {
+    let haystack_path = asset_path().join("haystack.png");
+    let haystack = Bitmap::new(image::open(haystack_path).unwrap(), None);
+    let needle_path = asset_path().join("needle1.png");
+    let needle = Bitmap::new(image::open(needle_path).unwrap(), None);
+    let found = haystack.find_bitmap_match(&needle, None, None, None).unwrap();
+    assert_eq!(Some(found.rect.origin), haystack.find_bitmap(&needle, None, None, None));
+    assert_eq!(found.rect.size, needle.size);
+    assert_eq!(found.score, 1.0);
+    let every = haystack.find_every_bitmap_match(&needle, None, None, None);
+    assert_eq!(every.len() as u64, haystack.count_of_bitmap(&needle, None, None, None));
+}
+
+#[test]
+fn save_and_open_bitmap() {
+    let haystack = Bitmap::open(asset_path().join("haystack.png")).unwrap();
+    assert_eq!(haystack.scale, 1.0);
+
+    let path = std::env::temp_dir().join(format!("autopilot-scale-{}.png", std::process::id()));
+    let needle_path = asset_path().join("needle1.png");
+    let needle = Bitmap::new(image::open(needle_path).unwrap(), Some(2.0));
+    needle.save(&path).unwrap();
+    let opened = Bitmap::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(opened.scale, 2.0);
+    assert_eq!(opened.size, needle.size);
+    assert_eq!(opened, needle);
+}
+
+#[test]
+fn encoded_string_round_trip() {
+    let needle_path = asset_path().join("needle2.png");
+    let needle = Bitmap::new(image::open(needle_path).unwrap().to_rgb8().into(), Some(2.0));
+    let encoded = needle.to_encoded_string();
+    assert!(encoded.starts_with(&format!(
+        "b{},{},",
+        needle.image.width(),
+        needle.image.height()
+    )));
+    assert!(encoded.ends_with(",2"));
+    let decoded = Bitmap::from_encoded_string(&encoded).unwrap();
+    assert_eq!(decoded.scale, 2.0);
+    assert_eq!(decoded, needle);
+    assert!(Bitmap::from_encoded_string("b1,1,!!").is_err());
+}
+
+#[test]
+fn golden_image() {
+    let haystack = Bitmap::open(asset_path().join("haystack.png")).unwrap();
+    let needle = Bitmap::open(asset_path().join("needle1.png")).unwrap();
+    let matches: Vec<_> = haystack
+        .find_every_bitmap(&needle, None, None, None)
+        .into_iter()
+        .map(|point| Rect::new(point, needle.size))
+        .collect();
+    let annotated = haystack.annotated(None, None, &matches);
+    golden::assert_golden(
+        &annotated,
+        asset_path().join("goldens").join("annotated_haystack.png"),
+        None,
+        &[],
+    );
+}
+
+#[test]
+fn find_bitmap_debug() {
+    let haystack = Bitmap::open(asset_path().join("haystack.png")).unwrap();
+    let needle = Bitmap::open(asset_path().join("needle1.png")).unwrap();
+    let path = std::env::temp_dir().join(format!("autopilot-debug-{}.png", std::process::id()));
+    let found = haystack
+        .find_bitmap_debug(&needle, None, None, None, &path)
+        .unwrap();
+    let annotated = Bitmap::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(found, haystack.find_bitmap(&needle, None, None, None));
+    assert_eq!(annotated.size, haystack.size);
+    assert!(annotated != haystack);
+}
+
 #[inline]
 fn asset_path() -> path::PathBuf {
     path::Path::new(file!()).parent().unwrap().join("assets")